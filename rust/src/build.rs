@@ -1,19 +1,294 @@
-use crate::types::{ClassType, DNSHeader, DNSMessage, DNSQuestion, Opcode, RecordType, ResourceRecord, QR, RCODE};
+use std::{collections::HashMap, net::IpAddr};
 
-pub fn build_message(message: DNSMessage) -> Vec<u8> {
-    let mut result: Vec<u8> = Vec::new();
-    result.extend_from_slice(&build_header(message.header));
-    result.extend_from_slice(&build_questions(message.questions));
-    result.extend_from_slice(&build_records(message.answers));
+use bytes::BufMut;
+use thiserror::Error;
+
+use crate::{
+    parse::HEADER_LEN,
+    types::{DNSHeader, DNSMessage, DNSQuestion, Edns, EdnsOption, Name, Opcode, RData, RecordType, ResourceRecord, QR, RCODE},
+};
+
+// Compression pointers only have 14 bits for the offset, so names written past this point can't be pointed to
+const MAX_POINTER_OFFSET: usize = 0b0011_1111_1111_1111;
+
+// The longest a message can be, since TCP only has 16 bits for its length
+const MAX_MESSAGE_LEN: usize = u16::MAX as usize;
 
-    result
+// A <character-string> has a single byte for its length
+const MAX_CHARACTER_STRING_LEN: usize = u8::MAX as usize;
+
+/// Reasons a message could not be encoded
+#[derive(Debug, Error, PartialEq, Eq, Clone, Copy)]
+pub enum BuildError {
+    #[error("{record_type} record has a {length} byte character-string, but they're limited to 255 bytes")]
+    CharacterStringTooLong { record_type: RecordType, length: usize },
+
+    #[error("the header and OPT record take {needed} bytes, but there's only room for {max_len}")]
+    NoRoom { needed: usize, max_len: usize },
 }
 
-fn build_header(h: DNSHeader) -> Vec<u8> {
+/// Builds a message with compressed names
+pub fn build_message(message: DNSMessage) -> Result<Vec<u8>, BuildError> {
+    build_message_with(message, true)
+}
+
+/// Builds a message, optionally compressing names as described in RFC 1035 section 4.1.4. Compression should be disabled when the
+/// message needs to be in canonical wire form, e.g. for DNSSEC.
+pub fn build_message_with(message: DNSMessage, compress: bool) -> Result<Vec<u8>, BuildError> {
     let mut result: Vec<u8> = Vec::new();
+    encode_message(&mut result, &message, MAX_MESSAGE_LEN, compress)?;
+
+    Ok(result)
+}
+
+/// Puts a message together section by section. The header counts are worked out from the sections when it's built, so they can't
+/// disagree with what's actually in the message.
+#[derive(Debug, Clone)]
+pub struct MessageBuilder {
+    message: DNSMessage,
+}
+
+impl MessageBuilder {
+    /// Starts a standard query asking for recursion
+    pub fn query(id: u16) -> Self {
+        MessageBuilder::new(DNSHeader {
+            id,
+            qr: QR::Query,
+            opcode: Opcode::QUERY,
+            aa: false, tc: false, rd: true, ra: false, z: 0, ad: false, cd: false,
+            rcode: RCODE::NoError,
+            qdcount: 0, ancount: 0, nscount: 0, arcount: 0,
+        })
+    }
+
+    /// Starts a response to a query, with the query's ID, opcode, RD and CD bits and questions copied over (RFC 1035 section 4.1.1,
+    /// RFC 6840 section 5.9)
+    pub fn response(query: &DNSMessage) -> Self {
+        let mut builder = MessageBuilder::new(DNSHeader {
+            id: query.header.id,
+            qr: QR::Response,
+            opcode: query.header.opcode,
+            aa: false, tc: false, rd: query.header.rd, ra: false, z: 0, ad: false, cd: query.header.cd,
+            rcode: RCODE::NoError,
+            qdcount: 0, ancount: 0, nscount: 0, arcount: 0,
+        });
+
+        builder.message.questions = query.questions.clone();
+        builder
+    }
+
+    fn new(header: DNSHeader) -> Self {
+        MessageBuilder {
+            message: DNSMessage {
+                header,
+                questions: Vec::new(),
+                answers: Vec::new(),
+                authorities: Vec::new(),
+                additionals: Vec::new(),
+                edns: None,
+            },
+        }
+    }
+
+    pub fn question(mut self, question: DNSQuestion) -> Self {
+        self.message.questions.push(question);
+        self
+    }
+
+    pub fn answer(mut self, record: ResourceRecord) -> Self {
+        self.message.answers.push(record);
+        self
+    }
 
+    pub fn authority(mut self, record: ResourceRecord) -> Self {
+        self.message.authorities.push(record);
+        self
+    }
+
+    pub fn additional(mut self, record: ResourceRecord) -> Self {
+        self.message.additionals.push(record);
+        self
+    }
+
+    /// Sets the OPT record, or leaves it out with None
+    pub fn edns(mut self, edns: Option<Edns>) -> Self {
+        self.message.edns = edns;
+        self
+    }
+
+    /// Sets the RCODE. Extended RCODEs need an OPT record to be sent in full.
+    pub fn rcode(mut self, rcode: RCODE) -> Self {
+        self.message.header.rcode = rcode;
+        self
+    }
+
+    pub fn tc(mut self, tc: bool) -> Self {
+        self.message.header.tc = tc;
+        self
+    }
+
+    pub fn rd(mut self, rd: bool) -> Self {
+        self.message.header.rd = rd;
+        self
+    }
+
+    pub fn ad(mut self, ad: bool) -> Self {
+        self.message.header.ad = ad;
+        self
+    }
+
+    pub fn cd(mut self, cd: bool) -> Self {
+        self.message.header.cd = cd;
+        self
+    }
+
+    pub fn build(self) -> DNSMessage {
+        let mut message = self.message;
+
+        message.header.qdcount = message.questions.len() as u16;
+        message.header.ancount = message.answers.len() as u16;
+        message.header.nscount = message.authorities.len() as u16;
+        message.header.arcount = message.additionals.len() as u16;
+
+        message
+    }
+}
+
+/// The sections of a message that didn't fit when it was encoded
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct Truncated {
+    pub questions: bool,
+    pub answers: bool,
+    pub authorities: bool,
+    pub additionals: bool,
+}
+
+impl Truncated {
+    /// Whether the message needs the TC bit. Additional records can be left out without it, since they only save the client some
+    /// lookups (RFC 2181 section 9).
+    pub fn needs_tc(&self) -> bool {
+        self.questions || self.answers || self.authorities
+    }
+}
+
+/// Appends a message to `buf`, using at most `max_len` bytes, or however much room is left in `buf` if that's less. Questions and
+/// records are written in order until one doesn't fit, then it and everything after it are left out, and the header counts only
+/// cover what was written. The header and the OPT record are always written, so the client can still tell what the response was.
+///
+/// The header is written as it is apart from the counts, so it's up to the caller to set TC if the returned sections call for it.
+/// It's an error if there isn't room for the header and OPT record, or if a record can't be encoded at all, and then nothing is
+/// written.
+pub fn encode_message<B: BufMut>(buf: &mut B, message: &DNSMessage, max_len: usize, compress: bool) -> Result<Truncated, BuildError> {
+    // The OPT record is written last, but its room is set aside first so it can't be crowded out
+    let mut opt: Vec<u8> = Vec::new();
+    if let Some(edns) = &message.edns {
+        build_edns(&mut opt, edns, message.header.rcode);
+    }
+
+    let max_len = max_len.min(MAX_MESSAGE_LEN).min(buf.remaining_mut());
+    if HEADER_LEN + opt.len() > max_len {
+        return Err(BuildError::NoRoom { needed: HEADER_LEN + opt.len(), max_len });
+    }
+
+    let mut writer = EntryWriter {
+        body: Vec::new(),
+        names: NameTable::new(compress),
+        max_len: max_len - HEADER_LEN - opt.len(),
+        full: false,
+    };
+    writer.names.base = HEADER_LEN;
+
+    let qdcount = writer.write_section(&message.questions, |result, names, q| {
+        build_question(result, names, q);
+        Ok(())
+    })?;
+    let ancount = writer.write_section(&message.answers, build_record)?;
+    let nscount = writer.write_section(&message.authorities, build_record)?;
+    let arcount = writer.write_section(&message.additionals, build_record)?;
+
+    // The OPT record goes at the end of the additional section, and is counted along with the rest
+    let mut header = message.header;
+    header.qdcount = qdcount as u16;
+    header.ancount = ancount as u16;
+    header.nscount = nscount as u16;
+    header.arcount = (arcount + message.edns.is_some() as usize) as u16;
+
+    build_header(buf, header);
+    buf.put_slice(&writer.body);
+    buf.put_slice(&opt);
+
+    Ok(Truncated {
+        questions: qdcount < message.questions.len(),
+        answers: ancount < message.answers.len(),
+        authorities: nscount < message.authorities.len(),
+        additionals: arcount < message.additionals.len(),
+    })
+}
+
+// Puts together everything that comes after the header, one question or record at a time. The header counts depend on how many of
+// them fit, and BufMut can't go back to fill them in, so all of it is built before the header is written.
+struct EntryWriter {
+    body: Vec<u8>,
+    names: NameTable,
+    max_len: usize, // How long the body can be
+    full: bool, // Once something doesn't fit, nothing after it is written either
+}
+
+impl EntryWriter {
+    // Writes entries in order until one doesn't fit, returning how many were written
+    fn write_section<T>(
+        &mut self,
+        entries: &[T],
+        build: impl Fn(&mut Vec<u8>, &mut NameTable, &T) -> Result<(), BuildError>,
+    ) -> Result<usize, BuildError> {
+        let mut count = 0;
+
+        for entry in entries {
+            if !self.write(|result, names| build(result, names, entry))? {
+                break;
+            }
+
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    fn write(&mut self, build: impl FnOnce(&mut Vec<u8>, &mut NameTable) -> Result<(), BuildError>) -> Result<bool, BuildError> {
+        if self.full {
+            return Ok(false);
+        }
+
+        let start = self.body.len();
+        build(&mut self.body, &mut self.names)?;
+
+        // The names in an entry that didn't fit are left in the table, but nothing that could point to them is written afterwards
+        if self.body.len() > self.max_len {
+            self.body.truncate(start);
+            self.full = true;
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+}
+
+// Remembers where each name (and every suffix of it) was written in the message, so later copies can be replaced with a pointer
+struct NameTable {
+    compress: bool,
+    offsets: HashMap<Vec<u8>, u16>,
+    base: usize, // Where in the message the buffer being written to starts
+}
+
+impl NameTable {
+    fn new(compress: bool) -> Self {
+        NameTable { compress, offsets: HashMap::new(), base: 0 }
+    }
+}
+
+fn build_header(result: &mut impl BufMut, h: DNSHeader) {
     // Place the ID
-    result.extend_from_slice(&h.id.to_be_bytes());
+    result.put_u16(h.id);
 
     let mut byte_three: u8;
 
@@ -22,28 +297,23 @@ fn build_header(h: DNSHeader) -> Vec<u8> {
         QR::Query => byte_three = 0,
     }
 
-    let opcode: u8 = match h.opcode {
-        Opcode::QUERY => 0,
-        Opcode::IQUERY => 1,
-        Opcode::STATUS => 2,
-        Opcode::Reserved => 3, // Technically, this should encompass 3-15, but I don't care enough to implement the others
-    };
+    let opcode = u8::from(h.opcode);
 
-    byte_three = (opcode << 3) | byte_three;
+    byte_three |= opcode << 3;
 
     if h.aa {
-        byte_three = byte_three | 0b0000_0100;
+        byte_three |= 0b0000_0100;
     }
 
     if h.tc {
-        byte_three = byte_three | 0b0000_0010;
+        byte_three |= 0b0000_0010;
     }
 
     if h.rd {
-        byte_three = byte_three | 0b0000_0001;
+        byte_three |= 0b0000_0001;
     }
 
-    result.push(byte_three);
+    result.put_u8(byte_three);
 
     let mut byte_four: u8 = 0;
 
@@ -51,82 +321,237 @@ fn build_header(h: DNSHeader) -> Vec<u8> {
         byte_four = 0b1000_0000;
     }
 
-    byte_four = (h.z << 4) | byte_four;
+    byte_four |= (h.z & 0b1) << 6;
 
-    let rcode: u8 = match h.rcode {
-        RCODE::NoError => 0,
-        RCODE::FormatError => 1,
-        RCODE::ServerFailure => 2,
-        RCODE::NameError => 3,
-        RCODE::NotImplemented => 4,
-        RCODE::Refused => 5,
-        RCODE::Reserved => 6, // Technically, this should encompass 6-15, but I don't care enough to implement the others
-    };
+    if h.ad {
+        byte_four |= 0b0010_0000;
+    }
 
-    byte_four = rcode | byte_four;
+    if h.cd {
+        byte_four |= 0b0001_0000;
+    }
 
-    result.push(byte_four);
+    // Only the lower 4 bits fit in the header, encode_message puts the rest in the OPT record
+    let rcode = (u16::from(h.rcode) & 0b0000_1111) as u8;
 
-    result.extend_from_slice(&h.qdcount.to_be_bytes());
-    result.extend_from_slice(&h.ancount.to_be_bytes());
-    result.extend_from_slice(&h.nscount.to_be_bytes());
-    result.extend_from_slice(&h.arcount.to_be_bytes());
+    byte_four |= rcode;
 
-    result
+    result.put_u8(byte_four);
 
+    result.put_u16(h.qdcount);
+    result.put_u16(h.ancount);
+    result.put_u16(h.nscount);
+    result.put_u16(h.arcount);
 }
 
-fn build_questions(questions: Vec<DNSQuestion>) -> Vec<u8> {
-    let mut result: Vec<u8> = Vec::new();
-    for q in questions.iter() {
-        result.extend_from_slice(&q.qname);
+fn build_question(result: &mut Vec<u8>, names: &mut NameTable, q: &DNSQuestion) {
+    build_name(result, names, &q.qname);
 
-        let qtype: u16 = match q.qtype {
-            RecordType::A => 1,
-            RecordType::Other => 0, // This is wrong, but I only care about A-type records
-        };
+    result.extend_from_slice(&u16::from(q.qtype).to_be_bytes());
 
-        result.extend_from_slice(&qtype.to_be_bytes());
+    result.extend_from_slice(&u16::from(q.qclass).to_be_bytes());
+}
 
-        let qclass: u16 = match q.qclass {
-            ClassType::IN => 1,
-            ClassType::Other => 0, // This is wrong, but I only care about the IN class
-        };
+fn build_record(result: &mut Vec<u8>, names: &mut NameTable, r: &ResourceRecord) -> Result<(), BuildError> {
+    build_name(result, names, &r.name);
+
+    result.extend_from_slice(&u16::from(r.record_type).to_be_bytes());
+
+    result.extend_from_slice(&u16::from(r.class).to_be_bytes());
+    result.extend_from_slice(&r.ttl.to_be_bytes());
+
+    // Compressed names in the RDATA depend on where it starts, so it's written in place and RDLENGTH is filled in afterwards
+    let rdlength_idx = result.len();
+    result.extend_from_slice(&[0, 0]);
+
+    build_rdata(result, names, &r.rdata, r.record_type)?;
+
+    let rdlength = (result.len() - rdlength_idx - 2) as u16;
+    result[rdlength_idx..rdlength_idx + 2].copy_from_slice(&rdlength.to_be_bytes());
+
+    Ok(())
+}
 
-        result.extend_from_slice(&qclass.to_be_bytes());
+// Writes the OPT record for an EDNS message. Without one, only the lower 4 bits of an extended RCODE make it into the message.
+fn build_edns(result: &mut Vec<u8>, edns: &Edns, rcode: RCODE) {
+    result.push(0); // The owner is always the root
+
+    result.extend_from_slice(&u16::from(RecordType::OPT).to_be_bytes());
+    result.extend_from_slice(&edns.udp_payload_size.to_be_bytes());
+
+    // The TTL is made up of the upper 8 bits of the extended RCODE, the version, the DO bit and 15 reserved bits
+    let mut ttl = ((u16::from(rcode) >> 4) as u32) << 24;
+    ttl |= (edns.version as u32) << 16;
+    if edns.dnssec_ok {
+        ttl |= 0x8000;
     }
+    ttl |= (edns.z & 0x7FFF) as u32;
+
+    result.extend_from_slice(&ttl.to_be_bytes());
+
+    let rdlength_idx = result.len();
+    result.extend_from_slice(&[0, 0]);
 
-    result
+    for option in edns.options.iter() {
+        build_edns_option(result, option);
+    }
+
+    let rdlength = (result.len() - rdlength_idx - 2) as u16;
+    result[rdlength_idx..rdlength_idx + 2].copy_from_slice(&rdlength.to_be_bytes());
 }
 
-fn build_records(records: Vec<ResourceRecord>) -> Vec<u8> {
-    let mut result: Vec<u8> = Vec::new();
-    for r in records.iter() {
-        result.extend_from_slice(&r.name);
+fn build_edns_option(result: &mut Vec<u8>, option: &EdnsOption) {
+    result.extend_from_slice(&option.code().to_be_bytes());
 
-        let record_type: u16 = match r.record_type {
-            RecordType::A => 1,
-            RecordType::Other => 0,
-        };
+    let length_idx = result.len();
+    result.extend_from_slice(&[0, 0]);
 
-        result.extend_from_slice(&record_type.to_be_bytes());
+    match option {
+        EdnsOption::ClientSubnet { source_prefix, scope_prefix, address } => {
+            let (family, octets): (u16, Vec<u8>) = match address {
+                IpAddr::V4(a) => (1, a.octets().to_vec()),
+                IpAddr::V6(a) => (2, a.octets().to_vec()),
+            };
 
-        let class: u16 = match r.class {
-            ClassType::IN => 1,
-            ClassType::Other => 0,
-        };
+            result.extend_from_slice(&family.to_be_bytes());
+            result.push(*source_prefix);
+            result.push(*scope_prefix);
+
+            // Only the bytes that the source prefix covers are sent
+            let prefix_bytes = (*source_prefix as usize).div_ceil(8).min(octets.len());
+            result.extend_from_slice(&octets[..prefix_bytes]);
+        },
+
+        EdnsOption::Cookie { client, server } => {
+            result.extend_from_slice(client);
+            result.extend_from_slice(server);
+        },
+
+        EdnsOption::ExtendedError { info_code, extra_text } => {
+            result.extend_from_slice(&u16::from(*info_code).to_be_bytes());
+            result.extend_from_slice(extra_text.as_bytes());
+        },
+
+        EdnsOption::Unknown { data, .. } => result.extend_from_slice(data),
+    }
+
+    let length = (result.len() - length_idx - 2) as u16;
+    result[length_idx..length_idx + 2].copy_from_slice(&length.to_be_bytes());
+}
+
+fn build_rdata(result: &mut Vec<u8>, names: &mut NameTable, rdata: &RData, record_type: RecordType) -> Result<(), BuildError> {
+    // Only the record types from RFC 1035 may have compressed names in their RDATA (RFC 3597 section 4), the rest are written in full
+    match rdata {
+        RData::A(address) => result.extend_from_slice(&address.octets()),
+        RData::AAAA(address) => result.extend_from_slice(&address.octets()),
+        RData::NS(name) | RData::CNAME(name) | RData::PTR(name) => build_name(result, names, name),
+        RData::DNAME(name) => result.extend_from_slice(name.as_wire()),
+
+        RData::MX { preference, exchange } => {
+            result.extend_from_slice(&preference.to_be_bytes());
+            build_name(result, names, exchange);
+        },
+
+        RData::SOA { mname, rname, serial, refresh, retry, expire, minimum } => {
+            build_name(result, names, mname);
+            build_name(result, names, rname);
+            result.extend_from_slice(&serial.to_be_bytes());
+            result.extend_from_slice(&refresh.to_be_bytes());
+            result.extend_from_slice(&retry.to_be_bytes());
+            result.extend_from_slice(&expire.to_be_bytes());
+            result.extend_from_slice(&minimum.to_be_bytes());
+        },
+
+        // Each string is one <character-string>, just as they're parsed, so a longer value has to be split up by whoever makes the
+        // record
+        RData::TXT(strings) => {
+            for string in strings {
+                build_character_string(result, string, record_type)?;
+            }
+        },
+
+        RData::SRV { priority, weight, port, target } => {
+            result.extend_from_slice(&priority.to_be_bytes());
+            result.extend_from_slice(&weight.to_be_bytes());
+            result.extend_from_slice(&port.to_be_bytes());
+            result.extend_from_slice(target.as_wire());
+        },
+
+        RData::CAA { flags, tag, value } => {
+            result.push(*flags);
+            build_character_string(result, tag, record_type)?;
+            result.extend_from_slice(value);
+        },
+
+        RData::NAPTR { order, preference, flags, services, regexp, replacement } => {
+            result.extend_from_slice(&order.to_be_bytes());
+            result.extend_from_slice(&preference.to_be_bytes());
+            build_character_string(result, flags, record_type)?;
+            build_character_string(result, services, record_type)?;
+            build_character_string(result, regexp, record_type)?;
+            result.extend_from_slice(replacement.as_wire());
+        },
+
+        RData::Unknown { bytes, .. } => result.extend_from_slice(bytes),
+    }
+
+    Ok(())
+}
+
+// Writes a name, replacing the longest suffix that was already written with a pointer to it
+fn build_name(result: &mut Vec<u8>, names: &mut NameTable, name: &Name) {
+    let name = name.as_wire();
 
-        result.extend_from_slice(&class.to_be_bytes());
-        result.extend_from_slice(&r.ttl.to_be_bytes());
-        result.extend_from_slice(&r.rdlength.to_be_bytes());
-        result.extend_from_slice(&r.rdata);
+    if !names.compress {
+        result.extend_from_slice(name);
+        return;
     }
 
-    result
+    let mut label_idx = 0;
+
+    while let Some(&content_length) = name.get(label_idx) {
+        if content_length == 0 {
+            break;
+        }
+
+        // Names compare case-insensitively, so the suffixes are stored in lowercase
+        let suffix = name[label_idx..].to_ascii_lowercase();
+
+        if let Some(pointer) = names.offsets.get(&suffix) {
+            result.extend_from_slice(&name[..label_idx]);
+            result.extend_from_slice(&(0b1100_0000_0000_0000 | pointer).to_be_bytes());
+            return;
+        }
+
+        let offset = names.base + result.len() + label_idx;
+        if offset <= MAX_POINTER_OFFSET {
+            names.offsets.insert(suffix, offset as u16);
+        }
+
+        label_idx += 1 + content_length as usize;
+    }
+
+    result.extend_from_slice(name);
+}
+
+// Writes a length-prefixed <character-string>. Cutting off a longer string would change the record, so it's an error instead.
+fn build_character_string(result: &mut Vec<u8>, string: &[u8], record_type: RecordType) -> Result<(), BuildError> {
+    if string.len() > MAX_CHARACTER_STRING_LEN {
+        return Err(BuildError::CharacterStringTooLong { record_type, length: string.len() });
+    }
+
+    result.push(string.len() as u8);
+    result.extend_from_slice(string);
+
+    Ok(())
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::Ipv4Addr;
+
+    use crate::types::{ClassType, ExtendedErrorCode};
 
     // This is makes errors for non-matching byte arrays more helpful
     macro_rules! assert_bytes_eq {
@@ -147,6 +572,523 @@ mod tests {
         };
     }
 
+    #[test]
+    fn test_build_message() {
+        struct Test {
+            label: String,
+            message: DNSMessage,
+            compress: bool,
+            want: Vec<u8>,
+        }
+
+        let message = DNSMessage {
+            header: DNSHeader {
+                id: 1234,
+                qr: QR::Response,
+                opcode: Opcode::QUERY,
+                aa: false, tc: false, rd: true, ra: true, z: 0, ad: false, cd: false,
+                rcode: RCODE::NoError,
+                qdcount: 1, ancount: 1, nscount: 1, arcount: 1,
+            },
+            questions: vec![
+                DNSQuestion {
+                    qname: Name::from_wire(&[0x02, b'i', b'o', 0x00]).unwrap(),
+                    qtype: RecordType::A,
+                    qclass: ClassType::IN,
+                },
+            ],
+            answers: vec![
+                ResourceRecord {
+                    name: Name::from_wire(&[0x02, b'i', b'o', 0x00]).unwrap(),
+                    record_type: RecordType::A,
+                    class: ClassType::IN,
+                    ttl: 60,
+                    rdata: RData::A(Ipv4Addr::new(1, 2, 3, 4)),
+                },
+            ],
+            authorities: vec![
+                ResourceRecord {
+                    name: Name::from_wire(&[0x02, b'I', b'O', 0x00]).unwrap(),
+                    record_type: RecordType::NS,
+                    class: ClassType::IN,
+                    ttl: 60,
+                    rdata: RData::NS(Name::from_wire(&[0x03, b'n', b's', b'1', 0x02, b'i', b'o', 0x00]).unwrap()),
+                },
+            ],
+            additionals: vec![
+                ResourceRecord {
+                    name: Name::from_wire(&[0x03, b'n', b's', b'1', 0x02, b'i', b'o', 0x00]).unwrap(),
+                    record_type: RecordType::A,
+                    class: ClassType::IN,
+                    ttl: 60,
+                    rdata: RData::A(Ipv4Addr::new(5, 6, 7, 8)),
+                },
+            ],
+            edns: None,
+        };
+
+        let tests: Vec<Test> = vec![
+            Test {
+                label: "uncompressed".to_string(),
+                message: message.clone(),
+                compress: false,
+
+                want: vec![
+                    // HEADER
+                    0b0000_0100, 0b1101_0010,
+                    0b1000_0001,
+                    0b1000_0000,
+                    0x00, 0x01,
+                    0x00, 0x01,
+                    0x00, 0x01,
+                    0x00, 0x01,
+                    // QUESTION
+                    0x02, b'i', b'o', 0x00,
+                    0x00, 0x01,
+                    0x00, 0x01,
+                    // ANSWER
+                    0x02, b'i', b'o', 0x00,
+                    0x00, 0x01,
+                    0x00, 0x01,
+                    0x00, 0x00, 0x00, 0x3c,
+                    0x00, 0x04,
+                    1, 2, 3, 4,
+                    // AUTHORITY
+                    0x02, b'I', b'O', 0x00,
+                    0x00, 0x02,
+                    0x00, 0x01,
+                    0x00, 0x00, 0x00, 0x3c,
+                    0x00, 0x08,
+                    0x03, b'n', b's', b'1', 0x02, b'i', b'o', 0x00,
+                    // ADDITIONAL
+                    0x03, b'n', b's', b'1', 0x02, b'i', b'o', 0x00,
+                    0x00, 0x01,
+                    0x00, 0x01,
+                    0x00, 0x00, 0x00, 0x3c,
+                    0x00, 0x04,
+                    5, 6, 7, 8,
+                ],
+            },
+
+            Test {
+                label: "compressed".to_string(),
+                message: message.clone(),
+                compress: true,
+
+                want: vec![
+                    // HEADER
+                    0b0000_0100, 0b1101_0010,
+                    0b1000_0001,
+                    0b1000_0000,
+                    0x00, 0x01,
+                    0x00, 0x01,
+                    0x00, 0x01,
+                    0x00, 0x01,
+                    // QUESTION
+                    0x02, b'i', b'o', 0x00,
+                    0x00, 0x01,
+                    0x00, 0x01,
+                    // ANSWER
+                    0b1100_0000, 0b0000_1100,
+                    0x00, 0x01,
+                    0x00, 0x01,
+                    0x00, 0x00, 0x00, 0x3c,
+                    0x00, 0x04,
+                    1, 2, 3, 4,
+                    // AUTHORITY
+                    0b1100_0000, 0b0000_1100, // Names are case-insensitive
+                    0x00, 0x02,
+                    0x00, 0x01,
+                    0x00, 0x00, 0x00, 0x3c,
+                    0x00, 0x06,
+                    0x03, b'n', b's', b'1', 0b1100_0000, 0b0000_1100,
+                    // ADDITIONAL
+                    0b1100_0000, 0b0011_0000, // Points at the NS record's RDATA
+                    0x00, 0x01,
+                    0x00, 0x01,
+                    0x00, 0x00, 0x00, 0x3c,
+                    0x00, 0x04,
+                    5, 6, 7, 8,
+                ],
+            },
+
+            Test {
+                label: "EDNS".to_string(),
+                message: DNSMessage {
+                    header: DNSHeader {
+                        id: 1234,
+                        qr: QR::Response,
+                        opcode: Opcode::QUERY,
+                        aa: false, tc: false, rd: false, ra: false, z: 0, ad: false, cd: false,
+                        rcode: RCODE::BadCookie,
+                        qdcount: 0, ancount: 0, nscount: 0, arcount: 0,
+                    },
+                    questions: Vec::new(),
+                    answers: Vec::new(),
+                    authorities: Vec::new(),
+                    additionals: Vec::new(),
+                    edns: Some(Edns {
+                        udp_payload_size: 1232,
+                        version: 0,
+                        dnssec_ok: true,
+                        z: 0,
+                        options: vec![
+                            EdnsOption::Unknown { code: 65001, data: vec![0xab, 0xcd] },
+                            EdnsOption::ClientSubnet {
+                                source_prefix: 20,
+                                scope_prefix: 16,
+                                address: IpAddr::V4(Ipv4Addr::new(192, 0, 16, 0)),
+                            },
+                            EdnsOption::Cookie { client: [1, 2, 3, 4, 5, 6, 7, 8], server: vec![9, 10, 11, 12, 13, 14, 15, 16] },
+                            EdnsOption::ExtendedError { info_code: ExtendedErrorCode::Blocked, extra_text: "no".to_string() },
+                        ],
+                    }),
+                },
+                compress: true,
+
+                want: vec![
+                    // HEADER
+                    0b0000_0100, 0b1101_0010,
+                    0b1000_0000,
+                    0b0000_0111, // Lower 4 bits of BADCOOKIE
+                    0x00, 0x00,
+                    0x00, 0x00,
+                    0x00, 0x00,
+                    0x00, 0x01, // The OPT record is counted
+                    // ADDITIONAL
+                    0x00,
+                    0x00, 0x29,
+                    0x04, 0xd0,
+                    0x01, 0x00, 0x80, 0x00, // Upper 8 bits of BADCOOKIE, version 0 and the DO bit
+                    0x00, 0x2d,
+                    0xfd, 0xe9, 0x00, 0x02, 0xab, 0xcd,
+                    0x00, 0x08, 0x00, 0x07, // ECS
+                    0x00, 0x01, 20, 16,
+                    192, 0, 16,
+                    0x00, 0x0a, 0x00, 0x10, // COOKIE
+                    1, 2, 3, 4, 5, 6, 7, 8,
+                    9, 10, 11, 12, 13, 14, 15, 16,
+                    0x00, 0x0f, 0x00, 0x04, // EDE
+                    0x00, 0x0f, b'n', b'o', // Blocked
+                ],
+            },
+        ];
+
+        for t in tests {
+            println!("Running test \"{}\"", t.label);
+            let got_data = build_message_with(t.message, t.compress).unwrap();
+            assert_bytes_eq!(got_data, t.want);
+        }
+    }
+
+    #[test]
+    fn test_message_builder() {
+        struct Test {
+            label: String,
+            got: DNSMessage,
+            want: DNSMessage,
+        }
+
+        let io = Name::from_wire(&[0x02, b'i', b'o', 0x00]).unwrap();
+        let question = DNSQuestion { qname: io.clone(), qtype: RecordType::A, qclass: ClassType::IN };
+        let answer = ResourceRecord {
+            name: io.clone(),
+            record_type: RecordType::A,
+            class: ClassType::IN,
+            ttl: 60,
+            rdata: RData::A(Ipv4Addr::new(1, 2, 3, 4)),
+        };
+        let edns = Edns { udp_payload_size: 1232, version: 0, dnssec_ok: false, z: 0, options: Vec::new() };
+
+        let query = DNSMessage {
+            header: DNSHeader {
+                id: 1234,
+                qr: QR::Query,
+                opcode: Opcode::QUERY,
+                aa: false, tc: false, rd: true, ra: false, z: 0, ad: false, cd: false,
+                rcode: RCODE::NoError,
+                qdcount: 1, ancount: 0, nscount: 0, arcount: 0,
+            },
+            questions: vec![question.clone()],
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+            edns: Some(edns.clone()),
+        };
+
+        // The query's answer section shouldn't make it into the response
+        let odd_query = DNSMessage {
+            header: DNSHeader { opcode: Opcode::UPDATE, rd: false, ra: true, aa: true, cd: true, ancount: 1, ..query.header },
+            answers: vec![answer.clone()],
+            edns: None,
+            ..query.clone()
+        };
+
+        let tests: Vec<Test> = vec![
+            Test {
+                label: "query".to_string(),
+                got: MessageBuilder::query(1234).question(question.clone()).edns(Some(edns.clone())).build(),
+                want: query.clone(),
+            },
+
+            Test {
+                label: "response".to_string(),
+                got: MessageBuilder::response(&query)
+                    .answer(answer.clone())
+                    .authority(answer.clone())
+                    .additional(answer.clone())
+                    .additional(answer.clone())
+                    .rcode(RCODE::BadCookie)
+                    .edns(Some(edns.clone()))
+                    .build(),
+                want: DNSMessage {
+                    header: DNSHeader {
+                        qr: QR::Response,
+                        rcode: RCODE::BadCookie,
+                        qdcount: 1, ancount: 1, nscount: 1, arcount: 2,
+                        ..query.header
+                    },
+                    answers: vec![answer.clone()],
+                    authorities: vec![answer.clone()],
+                    additionals: vec![answer.clone(), answer.clone()],
+                    ..query.clone()
+                },
+            },
+
+            Test {
+                label: "response copies only the ID, opcode, RD, CD and questions".to_string(),
+                got: MessageBuilder::response(&odd_query).build(),
+                want: DNSMessage {
+                    header: DNSHeader {
+                        qr: QR::Response,
+                        opcode: Opcode::UPDATE,
+                        rd: false, cd: true,
+                        ..query.header
+                    },
+                    edns: None,
+                    ..query.clone()
+                },
+            },
+        ];
+
+        for t in tests {
+            println!("Running test \"{}\"", t.label);
+            assert_eq!(t.got, t.want);
+        }
+    }
+
+    #[test]
+    fn test_encode_message() {
+        // What the message is written into
+        enum Buffer {
+            Growable,
+            Fixed(usize), // A slice with this much room
+            Chained(usize, usize), // Two slices with this much room each, written one after the other
+        }
+
+        struct Test {
+            label: String,
+            buffer: Buffer,
+            prefix: Vec<u8>, // Already in the buffer
+            max_len: usize,
+            want_len: usize,
+            want_counts: [usize; 4], // Not counting the OPT record
+            want_truncated: Truncated,
+            want_tc: bool,
+        }
+
+        let io = Name::from_wire(&[0x02, b'i', b'o', 0x00]).unwrap();
+        let ns1 = Name::from_wire(&[0x03, b'n', b's', b'1', 0x02, b'i', b'o', 0x00]).unwrap();
+        let record = |name: &Name, record_type: RecordType, rdata: RData| ResourceRecord {
+            name: name.clone(),
+            record_type,
+            class: ClassType::IN,
+            ttl: 60,
+            rdata,
+        };
+
+        // 12 bytes of header, 8 of question, 16 for each answer, 18 of authority, 16 of additional and 11 of OPT record
+        let message = DNSMessage {
+            header: DNSHeader {
+                id: 1234,
+                qr: QR::Response,
+                opcode: Opcode::QUERY,
+                aa: false, tc: false, rd: true, ra: true, z: 0, ad: false, cd: false,
+                rcode: RCODE::NoError,
+                qdcount: 1, ancount: 2, nscount: 1, arcount: 1,
+            },
+            questions: vec![DNSQuestion { qname: io.clone(), qtype: RecordType::A, qclass: ClassType::IN }],
+            answers: vec![
+                record(&io, RecordType::A, RData::A(Ipv4Addr::new(1, 2, 3, 4))),
+                record(&io, RecordType::A, RData::A(Ipv4Addr::new(5, 6, 7, 8))),
+            ],
+            authorities: vec![record(&io, RecordType::NS, RData::NS(ns1.clone()))],
+            additionals: vec![record(&ns1, RecordType::A, RData::A(Ipv4Addr::new(9, 10, 11, 12)))],
+            edns: Some(Edns { udp_payload_size: 1232, version: 0, dnssec_ok: false, z: 0, options: Vec::new() }),
+        };
+
+        let tests: Vec<Test> = vec![
+            Test {
+                label: "everything fits".to_string(),
+                buffer: Buffer::Growable,
+                prefix: Vec::new(),
+                max_len: 97,
+                want_len: 97,
+                want_counts: [1, 2, 1, 1],
+                want_truncated: Truncated::default(),
+                want_tc: false,
+            },
+
+            Test {
+                label: "additional left out".to_string(),
+                buffer: Buffer::Growable,
+                prefix: Vec::new(),
+                max_len: 96,
+                want_len: 81,
+                want_counts: [1, 2, 1, 0],
+                want_truncated: Truncated { additionals: true, ..Truncated::default() },
+                want_tc: false,
+            },
+
+            Test {
+                label: "answers cut short".to_string(),
+                buffer: Buffer::Growable,
+                prefix: Vec::new(),
+                max_len: 62,
+                want_len: 47,
+                want_counts: [1, 1, 0, 0],
+                want_truncated: Truncated { answers: true, authorities: true, additionals: true, ..Truncated::default() },
+                want_tc: true,
+            },
+
+            Test {
+                label: "only the header and OPT record".to_string(),
+                buffer: Buffer::Growable,
+                prefix: Vec::new(),
+                max_len: 23,
+                want_len: 23,
+                want_counts: [0, 0, 0, 0],
+                want_truncated: Truncated { questions: true, answers: true, authorities: true, additionals: true },
+                want_tc: true,
+            },
+
+            // Compression pointers are relative to the start of the message, not the buffer
+            Test {
+                label: "after other data".to_string(),
+                buffer: Buffer::Growable,
+                prefix: vec![0x00, 0x61],
+                max_len: 97,
+                want_len: 97,
+                want_counts: [1, 2, 1, 1],
+                want_truncated: Truncated::default(),
+                want_tc: false,
+            },
+
+            Test {
+                label: "buffer with less room than the limit".to_string(),
+                buffer: Buffer::Fixed(64),
+                prefix: vec![0x00, 0x61],
+                max_len: 512,
+                want_len: 47,
+                want_counts: [1, 1, 0, 0],
+                want_truncated: Truncated { answers: true, authorities: true, additionals: true, ..Truncated::default() },
+                want_tc: true,
+            },
+
+            Test {
+                label: "chained buffers".to_string(),
+                buffer: Buffer::Chained(40, 60),
+                prefix: Vec::new(),
+                max_len: 512,
+                want_len: 97,
+                want_counts: [1, 2, 1, 1],
+                want_truncated: Truncated::default(),
+                want_tc: false,
+            },
+        ];
+
+        for t in tests {
+            println!("Running test \"{}\"", t.label);
+            let mut growable = bytes::BytesMut::new();
+            let mut fixed: Vec<u8> = vec![0; 100];
+
+            let (got_truncated, buf): (Truncated, &[u8]) = match t.buffer {
+                Buffer::Growable => {
+                    growable.put_slice(&t.prefix);
+                    (encode_message(&mut growable, &message, t.max_len, true).unwrap(), &growable)
+                },
+
+                Buffer::Fixed(room) => {
+                    let mut buf = &mut fixed[..room];
+                    buf.put_slice(&t.prefix);
+                    let got_truncated = encode_message(&mut buf, &message, t.max_len, true).unwrap();
+                    let len = room - buf.len();
+                    (got_truncated, &fixed[..len])
+                },
+
+                // The two parts of the chain are next to each other, so what's written ends up in one piece
+                Buffer::Chained(first, second) => {
+                    let (a, b) = fixed[..first + second].split_at_mut(first);
+                    let mut buf = a.chain_mut(b);
+                    buf.put_slice(&t.prefix);
+                    let got_truncated = encode_message(&mut buf, &message, t.max_len, true).unwrap();
+                    let len = first + second - buf.remaining_mut();
+                    (got_truncated, &fixed[..len])
+                },
+            };
+
+            assert_eq!(got_truncated, t.want_truncated);
+            assert_eq!(got_truncated.needs_tc(), t.want_tc);
+            assert_eq!(&buf[..t.prefix.len()], &t.prefix[..]);
+            assert_eq!(buf.len() - t.prefix.len(), t.want_len);
+
+            let got = crate::parse::parse_message(&buf[t.prefix.len()..]).unwrap();
+            let got_counts = [got.questions.len(), got.answers.len(), got.authorities.len(), got.additionals.len()];
+            assert_eq!(got_counts, t.want_counts);
+            assert_eq!(got.answers[..], message.answers[..got_counts[1]]);
+            assert_eq!(got.edns, message.edns);
+        }
+    }
+
+    #[test]
+    fn test_encode_message_no_room() {
+        struct Test {
+            label: String,
+            room: usize, // In the buffer
+            max_len: usize,
+            want: BuildError,
+        }
+
+        let message = MessageBuilder::query(1234)
+            .edns(Some(Edns { udp_payload_size: 1232, version: 0, dnssec_ok: false, z: 0, options: Vec::new() }))
+            .build();
+
+        let tests: Vec<Test> = vec![
+            Test {
+                label: "limit too small for the OPT record".to_string(),
+                room: 100,
+                max_len: 22,
+                want: BuildError::NoRoom { needed: 23, max_len: 22 },
+            },
+
+            Test {
+                label: "buffer too small for the header".to_string(),
+                room: 10,
+                max_len: 512,
+                want: BuildError::NoRoom { needed: 23, max_len: 10 },
+            },
+        ];
+
+        for t in tests {
+            println!("Running test \"{}\"", t.label);
+            let mut fixed: Vec<u8> = vec![0; t.room];
+            let mut buf = &mut fixed[..];
+
+            assert_eq!(encode_message(&mut buf, &message, t.max_len, true), Err(t.want));
+            assert_eq!(buf.len(), t.room); // Nothing was written
+        }
+    }
+
     #[test]
     fn test_build_header() {
         struct Test {
@@ -166,7 +1108,9 @@ mod tests {
                     tc:     true,
                     rd:     false,
                     ra:     true,
-                    z:      0b101,
+                    z:      1,
+                    ad:     false,
+                    cd:     true,
                     rcode:  RCODE::NotImplemented,
                     qdcount: 0, ancount: 0, nscount: 0, arcount: 0,
                 },
@@ -196,7 +1140,9 @@ mod tests {
                     tc:     false,
                     rd:     true,
                     ra:     false,
-                    z:      0b110,
+                    z:      1,
+                    ad:     true,
+                    cd:     false,
                     rcode:  RCODE::NameError,
                     qdcount: 0, ancount: 0, nscount: 0, arcount: 0,
                 },
@@ -220,7 +1166,8 @@ mod tests {
 
         for t in tests {
             println!("Running test \"{}\"", t.label);
-            let got_data = build_header(t.h);
+            let mut got_data: Vec<u8> = Vec::new();
+            build_header(&mut got_data, t.h);
             assert_bytes_eq!(got_data, t.want);
         }
     }
@@ -238,13 +1185,13 @@ mod tests {
                 label: "basic test 1".to_string(),
                 questions: vec![
                     DNSQuestion {
-                        qname: vec![
+                        qname: Name::from_wire(&[
                             0x07,
                             b'e', b'x', b'a', b'm', b'p', b'l', b'e',
                             0x03,
                             b'c', b'o', b'm',
                             0x00,
-                        ],
+                        ]).unwrap(),
 
                         qtype: RecordType::A,
                         qclass: ClassType::IN,
@@ -260,12 +1207,77 @@ mod tests {
                     0x00, 0x01,
                     0x00, 0x01,
                 ]
-            }
+            },
+
+            Test {
+                label: "non-A record types".to_string(),
+                questions: vec![
+                    DNSQuestion {
+                        qname: Name::from_wire(&[0x02, b'i', b'o', 0x00]).unwrap(),
+                        qtype: RecordType::AAAA,
+                        qclass: ClassType::IN,
+                    },
+
+                    DNSQuestion {
+                        qname: Name::from_wire(&[0x02, b'i', b'o', 0x00]).unwrap(),
+                        qtype: RecordType::Unknown(0xff00),
+                        qclass: ClassType::IN,
+                    },
+                ],
+
+                want: vec![
+                    0x02, b'i', b'o', 0x00,
+                    0x00, 0x1c,
+                    0x00, 0x01,
+                    0x02, b'i', b'o', 0x00,
+                    0xff, 0x00,
+                    0x00, 0x01,
+                ]
+            },
+
+            Test {
+                label: "non-IN classes".to_string(),
+                questions: vec![
+                    DNSQuestion {
+                        qname: Name::from_wire(&[0x07, b'v', b'e', b'r', b's', b'i', b'o', b'n', 0x04, b'b', b'i', b'n', b'd', 0x00]).unwrap(),
+                        qtype: RecordType::TXT,
+                        qclass: ClassType::CH,
+                    },
+
+                    DNSQuestion {
+                        qname: Name::from_wire(&[0x02, b'i', b'o', 0x00]).unwrap(),
+                        qtype: RecordType::A,
+                        qclass: ClassType::NONE,
+                    },
+
+                    DNSQuestion {
+                        qname: Name::from_wire(&[0x02, b'i', b'o', 0x00]).unwrap(),
+                        qtype: RecordType::A,
+                        qclass: ClassType::Unknown(0x1234),
+                    },
+                ],
+
+                want: vec![
+                    0x07, b'v', b'e', b'r', b's', b'i', b'o', b'n', 0x04, b'b', b'i', b'n', b'd', 0x00,
+                    0x00, 0x10,
+                    0x00, 0x03,
+                    0x02, b'i', b'o', 0x00,
+                    0x00, 0x01,
+                    0x00, 0xfe,
+                    0x02, b'i', b'o', 0x00,
+                    0x00, 0x01,
+                    0x12, 0x34,
+                ]
+            },
         ];
 
         for t in tests {
             println!("Running test \"{}\"", t.label);
-            let got_data = build_questions(t.questions);
+            let mut got_data: Vec<u8> = Vec::new();
+            let mut names = NameTable::new(false);
+            for q in t.questions.iter() {
+                build_question(&mut got_data, &mut names, q);
+            }
             assert_bytes_eq!(got_data, t.want);
         }
     }
@@ -281,18 +1293,17 @@ mod tests {
         let tests: Vec<Test> = vec![Test {
                 label: "basic test".to_string(),
                 records: vec![ResourceRecord{
-                    name: vec![
+                    name: Name::from_wire(&[
                         0x07,
                         b'e', b'x', b'a', b'm', b'p', b'l', b'e',
                         0x03,
                         b'c', b'o', b'm',
                         0x00,
-                    ],
-                    record_type: RecordType::A,
+                    ]).unwrap(),
+                    record_type: RecordType::TXT,
                     class: ClassType::IN,
                     ttl: 0,
-                    rdlength: 13,
-                    rdata: "hello, world!".to_string().into_bytes(),
+                    rdata: RData::TXT(vec!["hello, world!".to_string().into_bytes()]),
                 }],
 
                 want: vec![
@@ -301,18 +1312,140 @@ mod tests {
                     0x03,
                     b'c', b'o', b'm',
                     0x00,
+                    0x00, 0x10,
+                    0x00, 0x01,
+                    0x00, 0x00, 0x00, 0x00,
+                    0x00, 0b0000_1110,
+                    0b0000_1101, b'h', b'e', b'l', b'l', b'o', b',', b' ', b'w', b'o', b'r', b'l', b'd', b'!',
+                ],
+            },
+
+            Test {
+                label: "rdata test".to_string(),
+                records: vec![
+                    ResourceRecord {
+                        name: Name::from_wire(&[0x02, b'i', b'o', 0x00]).unwrap(),
+                        record_type: RecordType::A,
+                        class: ClassType::IN,
+                        ttl: 300,
+                        rdata: RData::A(Ipv4Addr::new(192, 168, 0, 6)),
+                    },
+
+                    ResourceRecord {
+                        name: Name::from_wire(&[0x02, b'i', b'o', 0x00]).unwrap(),
+                        record_type: RecordType::MX,
+                        class: ClassType::IN,
+                        ttl: 0,
+                        rdata: RData::MX { preference: 10, exchange: Name::from_wire(&[0x02, b'm', b'x', 0x02, b'i', b'o', 0x00]).unwrap() },
+                    },
+
+                    ResourceRecord {
+                        name: Name::from_wire(&[0x02, b'i', b'o', 0x00]).unwrap(),
+                        record_type: RecordType::CAA,
+                        class: ClassType::IN,
+                        ttl: 0,
+                        rdata: RData::CAA { flags: 0, tag: b"issue".to_vec(), value: b"ca.io".to_vec() },
+                    },
+                ],
+
+                want: vec![
+                    0x02, b'i', b'o', 0x00,
                     0x00, 0x01,
                     0x00, 0x01,
+                    0x00, 0x00, 0x01, 0x2c,
+                    0x00, 0x04,
+                    192, 168, 0, 6,
+
+                    0x02, b'i', b'o', 0x00,
+                    0x00, 0x0f,
+                    0x00, 0x01,
+                    0x00, 0x00, 0x00, 0x00,
+                    0x00, 0x09,
+                    0x00, 0x0a,
+                    0x02, b'm', b'x', 0x02, b'i', b'o', 0x00,
+
+                    0x02, b'i', b'o', 0x00,
+                    0x01, 0x01,
+                    0x00, 0x01,
                     0x00, 0x00, 0x00, 0x00,
-                    0x00, 0b0000_1101,
-                    b'h', b'e', b'l', b'l', b'o', b',', b' ', b'w', b'o', b'r', b'l', b'd', b'!',
+                    0x00, 0x0c,
+                    0x00,
+                    0x05, b'i', b's', b's', b'u', b'e',
+                    b'c', b'a', b'.', b'i', b'o',
                 ],
         }];
 
         for t in tests {
             println!("Running test \"{}\"", t.label);
-            let got_data = build_records(t.records);
+            let mut got_data: Vec<u8> = Vec::new();
+            let mut names = NameTable::new(false);
+            for r in t.records.iter() {
+                build_record(&mut got_data, &mut names, r).unwrap();
+            }
             assert_bytes_eq!(got_data, t.want);
         }
     }
+
+    #[test]
+    fn test_build_character_strings() {
+        struct Test {
+            label: String,
+            rdata: RData,
+            record_type: RecordType,
+            want: Result<Vec<u8>, BuildError>,
+        }
+
+        let long = vec![b'a'; 256];
+
+        let tests: Vec<Test> = vec![
+            Test {
+                label: "empty TXT string".to_string(),
+                rdata: RData::TXT(vec![vec![], b"v".to_vec()]),
+                record_type: RecordType::TXT,
+                want: Ok(vec![0x00, 0x01, b'v']),
+            },
+
+            Test {
+                label: "TXT string that's too long".to_string(),
+                rdata: RData::TXT(vec![b"v".to_vec(), long.clone()]),
+                record_type: RecordType::TXT,
+                want: Err(BuildError::CharacterStringTooLong { record_type: RecordType::TXT, length: 256 }),
+            },
+
+            Test {
+                label: "longest TXT string".to_string(),
+                rdata: RData::TXT(vec![long[..255].to_vec()]),
+                record_type: RecordType::TXT,
+                want: Ok([&[0xff][..], &long[..255]].concat()),
+            },
+
+            Test {
+                label: "CAA tag that's too long".to_string(),
+                rdata: RData::CAA { flags: 0, tag: long.clone(), value: b"ca.io".to_vec() },
+                record_type: RecordType::CAA,
+                want: Err(BuildError::CharacterStringTooLong { record_type: RecordType::CAA, length: 256 }),
+            },
+
+            Test {
+                label: "NAPTR regexp that's too long".to_string(),
+                rdata: RData::NAPTR {
+                    order: 10,
+                    preference: 10,
+                    flags: b"u".to_vec(),
+                    services: b"E2U+sip".to_vec(),
+                    regexp: long.clone(),
+                    replacement: Name::from_wire(&[0x00]).unwrap(),
+                },
+                record_type: RecordType::NAPTR,
+                want: Err(BuildError::CharacterStringTooLong { record_type: RecordType::NAPTR, length: 256 }),
+            },
+        ];
+
+        for t in tests {
+            println!("Running test \"{}\"", t.label);
+            let mut got_data: Vec<u8> = Vec::new();
+            let got = build_rdata(&mut got_data, &mut NameTable::new(false), &t.rdata, t.record_type).map(|()| got_data);
+            assert_eq!(got, t.want);
+        }
+    }
 }
\ No newline at end of file
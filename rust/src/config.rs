@@ -0,0 +1,184 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use thiserror::Error;
+
+use crate::cookie::CookiePolicy;
+
+pub const USAGE: &str = "usage: dns-server [--listen <ip>:<port>]... [--resolver <ip>:<port>] [--ecs-prefix-v4 <bits>]
+                  [--ecs-prefix-v6 <bits>] [--ecs-privacy] [--cookie-policy <allow|tcp|queries per second>]";
+
+/// Where the server listens when no addresses are given
+pub const DEFAULT_LISTEN: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 2053);
+
+/// Settings for the server, taken from the command line
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>, // The addresses to serve clients on
+    pub resolver: Option<String>, // Where to forward queries to, they're resolved locally without one
+    pub ecs_prefix_v4: u8, // How much of a client's address to send upstream in an EDNS Client Subnet option, 0 sends none
+    pub ecs_prefix_v6: u8,
+    pub ecs_privacy: bool, // Replace subnets that clients send with their source address, except a /0 subnet, which asks for none
+    pub cookie_policy: CookiePolicy, // How to treat clients that don't have a valid server cookie
+}
+
+impl Default for Config {
+    // The prefix lengths recommended by RFC 7871 section 11.1
+    fn default() -> Self {
+        Config {
+            listen: vec![DEFAULT_LISTEN],
+            resolver: None,
+            ecs_prefix_v4: 24,
+            ecs_prefix_v6: 56,
+            ecs_privacy: false,
+            cookie_policy: CookiePolicy::Allow,
+        }
+    }
+}
+
+/// Problems with the command line arguments
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("{0} needs a value")]
+    MissingValue(String),
+
+    #[error("\"{value}\" is not a valid value for {flag}")]
+    InvalidValue { flag: String, value: String },
+
+    #[error("unknown argument \"{0}\"")]
+    UnknownArgument(String),
+}
+
+/// Reads the command line arguments, not including the program name
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Config, ConfigError> {
+    let mut config = Config::default();
+    let mut listen: Vec<SocketAddr> = Vec::new();
+    let mut args = args.into_iter();
+
+    while let Some(flag) = args.next() {
+        let mut value = || args.next().ok_or_else(|| ConfigError::MissingValue(flag.clone()));
+
+        match flag.as_str() {
+            "--listen" => listen.push(parse_listen(&flag, &value()?)?),
+            "--resolver" => config.resolver = Some(value()?),
+            "--ecs-prefix-v4" => config.ecs_prefix_v4 = parse_prefix(&flag, &value()?, 32)?,
+            "--ecs-prefix-v6" => config.ecs_prefix_v6 = parse_prefix(&flag, &value()?, 128)?,
+            "--ecs-privacy" => config.ecs_privacy = true,
+            "--cookie-policy" => config.cookie_policy = parse_cookie_policy(&flag, &value()?)?,
+            _ => return Err(ConfigError::UnknownArgument(flag)),
+        }
+    }
+
+    // Listen addresses given on the command line replace the default, rather than adding to it
+    if !listen.is_empty() {
+        config.listen = listen;
+    }
+
+    Ok(config)
+}
+
+fn parse_listen(flag: &str, value: &str) -> Result<SocketAddr, ConfigError> {
+    value.parse().map_err(|_| ConfigError::InvalidValue { flag: flag.to_string(), value: value.to_string() })
+}
+
+fn parse_prefix(flag: &str, value: &str, max: u8) -> Result<u8, ConfigError> {
+    match value.parse::<u8>() {
+        Ok(prefix) if prefix <= max => Ok(prefix),
+        _ => Err(ConfigError::InvalidValue { flag: flag.to_string(), value: value.to_string() }),
+    }
+}
+
+fn parse_cookie_policy(flag: &str, value: &str) -> Result<CookiePolicy, ConfigError> {
+    match value {
+        "allow" => Ok(CookiePolicy::Allow),
+        "tcp" => Ok(CookiePolicy::Tcp),
+        _ => match value.parse::<u32>() {
+            Ok(limit) => Ok(CookiePolicy::RateLimit(limit)),
+            Err(_) => Err(ConfigError::InvalidValue { flag: flag.to_string(), value: value.to_string() }),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args() {
+        struct Test {
+            label: String,
+            args: Vec<&'static str>,
+            want: Result<Config, ConfigError>,
+        }
+
+        let tests: Vec<Test> = vec![
+            Test {
+                label: "no arguments".to_string(),
+                args: vec![],
+                want: Ok(Config::default()),
+            },
+
+            Test {
+                label: "everything".to_string(),
+                args: vec![
+                    "--resolver", "1.1.1.1:53", "--ecs-prefix-v4", "16", "--ecs-prefix-v6", "0", "--ecs-privacy", "--cookie-policy", "tcp",
+                ],
+                want: Ok(Config {
+                    listen: vec![DEFAULT_LISTEN],
+                    resolver: Some("1.1.1.1:53".to_string()),
+                    ecs_prefix_v4: 16,
+                    ecs_prefix_v6: 0,
+                    ecs_privacy: true,
+                    cookie_policy: CookiePolicy::Tcp,
+                }),
+            },
+
+            Test {
+                label: "several listen addresses".to_string(),
+                args: vec!["--listen", "[::]:53", "--listen", "10.0.0.2:5353"],
+                want: Ok(Config { listen: vec!["[::]:53".parse().unwrap(), "10.0.0.2:5353".parse().unwrap()], ..Config::default() }),
+            },
+
+            Test {
+                label: "listen address without a port".to_string(),
+                args: vec!["--listen", "10.0.0.2"],
+                want: Err(ConfigError::InvalidValue { flag: "--listen".to_string(), value: "10.0.0.2".to_string() }),
+            },
+
+            Test {
+                label: "rate limited cookie policy".to_string(),
+                args: vec!["--cookie-policy", "100"],
+                want: Ok(Config { cookie_policy: CookiePolicy::RateLimit(100), ..Config::default() }),
+            },
+
+            Test {
+                label: "invalid cookie policy".to_string(),
+                args: vec!["--cookie-policy", "deny"],
+                want: Err(ConfigError::InvalidValue { flag: "--cookie-policy".to_string(), value: "deny".to_string() }),
+            },
+
+            Test {
+                label: "missing value".to_string(),
+                args: vec!["--resolver"],
+                want: Err(ConfigError::MissingValue("--resolver".to_string())),
+            },
+
+            Test {
+                label: "prefix too long".to_string(),
+                args: vec!["--ecs-prefix-v4", "33"],
+                want: Err(ConfigError::InvalidValue { flag: "--ecs-prefix-v4".to_string(), value: "33".to_string() }),
+            },
+
+            Test {
+                label: "unknown argument".to_string(),
+                args: vec!["--resolve", "1.1.1.1:53"],
+                want: Err(ConfigError::UnknownArgument("--resolve".to_string())),
+            },
+        ];
+
+        for t in tests {
+            println!("Running test \"{}\"", t.label);
+            let got = parse_args(t.args.into_iter().map(String::from));
+            assert_eq!(got, t.want);
+        }
+    }
+}
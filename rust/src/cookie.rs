@@ -0,0 +1,259 @@
+use std::{hash::Hasher, net::IpAddr, time::{SystemTime, UNIX_EPOCH}};
+
+use siphasher::sip::SipHasher24;
+
+use crate::types::{Edns, EdnsOption};
+
+// Server cookies are accepted for an hour after they're made, and up to 5 minutes before, to allow for clocks being a little off
+// between servers that share a secret (RFC 9018 section 4.3)
+const COOKIE_LIFETIME: u32 = 3600;
+const COOKIE_CLOCK_SKEW: u32 = 300;
+
+// The previous secret is kept after a rotation, so rotating no more often than cookies live means every cookie can still be checked
+const SECRET_LIFETIME: u32 = COOKIE_LIFETIME;
+
+const SERVER_COOKIE_VERSION: u8 = 1;
+const SERVER_COOKIE_LEN: usize = 16;
+
+/// What to do with queries that don't come with a valid server cookie, since their source address could be spoofed
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum CookiePolicy {
+    Allow,
+    RateLimit(u32), // Answer up to this many of them per second
+    Tcp, // Make the client prove its address, either by retrying with a cookie or over TCP
+}
+
+/// The current time as a cookie timestamp, which is in seconds since the Unix epoch and wraps around in 2106
+pub fn now() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as u32).unwrap_or(0)
+}
+
+/// Returns the client and server cookies from a message's COOKIE option, if it has one
+pub fn find_cookie(edns: Option<&Edns>) -> Option<([u8; 8], &[u8])> {
+    edns?.options.iter().find_map(|o| match o {
+        EdnsOption::Cookie { client, server } => Some((*client, server.as_slice())),
+        _ => None,
+    })
+}
+
+/// Makes and checks server cookies in the interoperable format from RFC 9018, so that servers sharing a secret accept each other's
+/// cookies. The secret rotates every hour.
+pub struct ServerCookies {
+    current: [u8; 16],
+    previous: [u8; 16],
+    rotated_at: u32,
+}
+
+impl ServerCookies {
+    pub fn new(now: u32) -> Result<Self, getrandom::Error> {
+        let mut current = [0; 16];
+        getrandom::getrandom(&mut current)?;
+
+        Ok(ServerCookies { current, previous: current, rotated_at: now })
+    }
+
+    fn rotate(&mut self, now: u32) -> Result<(), getrandom::Error> {
+        if now.wrapping_sub(self.rotated_at) < SECRET_LIFETIME {
+            return Ok(());
+        }
+
+        let mut next = [0; 16];
+        getrandom::getrandom(&mut next)?;
+
+        self.previous = self.current;
+        self.current = next;
+        self.rotated_at = now;
+
+        Ok(())
+    }
+
+    /// Makes a server cookie for a client. A fresh one should go in every response, so the client's cookie never gets too old.
+    pub fn generate(&mut self, client: [u8; 8], client_ip: IpAddr, now: u32) -> Vec<u8> {
+        // Without new randomness the current secret just stays in use a while longer
+        let _ = self.rotate(now);
+
+        server_cookie(&self.current, client, client_ip, now)
+    }
+
+    /// Checks that a server cookie was made for this client by us (or a server with the same secret) within its lifetime
+    pub fn validate(&mut self, client: [u8; 8], server: &[u8], client_ip: IpAddr, now: u32) -> bool {
+        let _ = self.rotate(now);
+
+        if server.len() != SERVER_COOKIE_LEN || server[0] != SERVER_COOKIE_VERSION {
+            return false;
+        }
+
+        // Timestamps use serial number arithmetic (RFC 1982), so cookies keep working when the clock wraps around
+        let timestamp = u32::from_be_bytes([server[4], server[5], server[6], server[7]]);
+        let age = now.wrapping_sub(timestamp) as i32;
+        if age > COOKIE_LIFETIME as i32 || age < -(COOKIE_CLOCK_SKEW as i32) {
+            return false;
+        }
+
+        [self.current, self.previous].iter().any(|secret| server_cookie(secret, client, client_ip, timestamp) == server)
+    }
+}
+
+// Version | Reserved | Timestamp | Hash, where the hash is SipHash-2-4 of the client cookie, the first three fields and the client's
+// address (RFC 9018 section 4)
+fn server_cookie(secret: &[u8; 16], client: [u8; 8], client_ip: IpAddr, timestamp: u32) -> Vec<u8> {
+    let mut cookie = vec![SERVER_COOKIE_VERSION, 0, 0, 0];
+    cookie.extend_from_slice(&timestamp.to_be_bytes());
+
+    let mut hasher = SipHasher24::new_with_key(secret);
+    hasher.write(&client);
+    hasher.write(&cookie);
+    match client_ip.to_canonical() {
+        IpAddr::V4(a) => hasher.write(&a.octets()),
+        IpAddr::V6(a) => hasher.write(&a.octets()),
+    }
+
+    // SipHash's output is defined as little-endian bytes
+    cookie.extend_from_slice(&hasher.finish().to_le_bytes());
+
+    cookie
+}
+
+/// The cookies the forwarder uses with its upstream server
+pub struct UpstreamCookie {
+    pub client: [u8; 8],
+    pub server: Vec<u8>, // Empty until the upstream sends one
+}
+
+impl UpstreamCookie {
+    // There's only ever one upstream, and the forwarder's address doesn't change, so a random client cookie is as good as one hashed
+    // from the two addresses (RFC 7873 section 4.1)
+    pub fn new() -> Result<Self, getrandom::Error> {
+        let mut client = [0; 8];
+        getrandom::getrandom(&mut client)?;
+
+        Ok(UpstreamCookie { client, server: Vec::new() })
+    }
+}
+
+/// Counts events in the current second, allowing at most `limit` of them
+pub struct RateLimiter {
+    limit: u32,
+    second: u32,
+    count: u32,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32) -> Self {
+        RateLimiter { limit, second: 0, count: 0 }
+    }
+
+    pub fn allow(&mut self, now: u32) -> bool {
+        if now != self.second {
+            self.second = now;
+            self.count = 0;
+        }
+
+        if self.count >= self.limit {
+            return false;
+        }
+
+        self.count += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_cookies() {
+        struct Test {
+            label: String,
+            client: [u8; 8],
+            client_ip: IpAddr,
+            made_at: u32,
+            checked_at: u32,
+            want: bool,
+        }
+
+        let client = [1, 2, 3, 4, 5, 6, 7, 8];
+        let client_ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let start = 1_700_000_000;
+
+        let tests: Vec<Test> = vec![
+            Test {
+                label: "fresh cookie".to_string(),
+                client, client_ip, made_at: start, checked_at: start + 10,
+                want: true,
+            },
+
+            Test {
+                label: "different client cookie".to_string(),
+                client: [8, 7, 6, 5, 4, 3, 2, 1], client_ip, made_at: start, checked_at: start + 10,
+                want: false,
+            },
+
+            Test {
+                label: "different address".to_string(),
+                client, client_ip: "192.0.2.2".parse().unwrap(), made_at: start, checked_at: start + 10,
+                want: false,
+            },
+
+            Test {
+                label: "expired cookie".to_string(),
+                client, client_ip, made_at: start, checked_at: start + COOKIE_LIFETIME + 1,
+                want: false,
+            },
+
+            Test {
+                label: "cookie from the future".to_string(),
+                client, client_ip, made_at: start + COOKIE_CLOCK_SKEW + 1, checked_at: start,
+                want: false,
+            },
+
+            Test {
+                label: "cookie made before the secret rotated".to_string(),
+                client, client_ip, made_at: start + SECRET_LIFETIME - 1, checked_at: start + SECRET_LIFETIME + 1,
+                want: true,
+            },
+
+            Test {
+                label: "clock wrapped around".to_string(),
+                client, client_ip, made_at: u32::MAX - 10, checked_at: 10,
+                want: true,
+            },
+        ];
+
+        for t in tests {
+            println!("Running test \"{}\"", t.label);
+            let mut cookies = ServerCookies::new(start).unwrap();
+
+            let server = cookies.generate(client, client_ip, t.made_at);
+            assert_eq!(server.len(), SERVER_COOKIE_LEN);
+            assert_eq!(&server[..8], &[&[1, 0, 0, 0][..], &t.made_at.to_be_bytes()].concat());
+
+            assert_eq!(cookies.validate(t.client, &server, t.client_ip, t.checked_at), t.want);
+        }
+    }
+
+    #[test]
+    fn test_server_cookie_hash() {
+        // The example from RFC 9018 appendix A.1
+        let secret = [
+            0xe5, 0xe9, 0x73, 0xe5, 0xa6, 0xb2, 0xa4, 0x3f, 0x48, 0xe7, 0xdc, 0x84, 0x9e, 0x37, 0xbf, 0xcf,
+        ];
+        let client = [0x24, 0x64, 0xc4, 0xab, 0xcf, 0x10, 0xc9, 0x57];
+
+        let got = server_cookie(&secret, client, "198.51.100.100".parse().unwrap(), 1559731985);
+        let want = vec![0x01, 0x00, 0x00, 0x00, 0x5c, 0xf7, 0x9f, 0x11, 0x1f, 0x81, 0x30, 0xc3, 0xee, 0xe2, 0x94, 0x80];
+
+        assert_eq!(got, want);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(2);
+
+        assert!(limiter.allow(100));
+        assert!(limiter.allow(100));
+        assert!(!limiter.allow(100));
+        assert!(limiter.allow(101));
+    }
+}
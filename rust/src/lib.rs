@@ -0,0 +1,17 @@
+//! A DNS server that either answers queries itself or forwards them to another resolver, along with the wire format codec it's
+//! built on.
+//!
+//! - [`types`] has the messages, records and other values that make up the protocol.
+//! - [`parse`] decodes messages, and [`view`] reads them in place without decoding everything.
+//! - [`build`] encodes messages, and has a [`build::MessageBuilder`] for putting them together.
+//! - [`server`] answers queries, and [`config`] and [`cookie`] are the pieces it's configured with.
+//! - [`tcp`] reads and writes the length-prefixed messages used over TCP.
+
+pub mod types;
+pub mod parse;
+pub mod build;
+pub mod view;
+pub mod config;
+pub mod cookie;
+pub mod server;
+pub mod tcp;
@@ -1,135 +1,298 @@
-use std::{env, error, net::UdpSocket};
-use anyhow::anyhow;
+use std::{
+    env, error,
+    io::{self, Write},
+    net::{self, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    process,
+    sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex},
+    thread,
+    time::Duration,
+};
 
-use crate::{build::build_message, parse::parse_message, types::{DNSMessage, DNSQuestion, ResourceRecord}};
-mod types;
-mod parse;
-mod build;
+use dns_server::{config, server::{Server, MAX_MESSAGE_LEN}, tcp};
+use signal_hook::{consts::{SIGINT, SIGTERM}, iterator::Signals};
+
+use pool::WorkerPool;
+use shutdown::Shutdown;
+use socket::ErrorCounter;
+
+// How the binary runs the server, which isn't part of the library
+mod pool;
+mod shutdown;
+mod socket;
+
+// Workers spend most of their time waiting on the forward server, so there are many more of them than CPUs. That way a slow upstream
+// only holds up the queries that are waiting on it.
+const WORKERS_PER_CPU: usize = 16;
+
+// How many UDP queries can wait for a worker. Queries past this are dropped, and the client will retry, which is better than
+// answering them once they're too old to matter.
+const UDP_QUEUE_LEN: usize = 1024;
+
+// How long a TCP client can go without sending anything before its connection is closed (RFC 7766 section 6.2.3)
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long writing a response to a TCP client can take. A client that stops reading would otherwise hold up every other response on
+// its connection for good, along with the thread writing it.
+const TCP_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The most TCP connections served at once, across all listeners. Each one has a thread, and idle ones are only closed after the
+// timeout, so this keeps clients that open lots of connections from using up the server.
+const MAX_TCP_CONNECTIONS: usize = 128;
+
+// How long to wait before accepting again after a transient error, which is usually running out of file descriptors. Trying again
+// straight away would just spin until a connection closes.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+// The most queries from one TCP connection answered at once. Past this, the connection isn't read from until one of them is done.
+const MAX_TCP_QUERIES_IN_FLIGHT: usize = 16;
+
+// How long queries already being answered get to finish once the server is asked to stop. This is long enough for one waiting on the
+// forward server to time out and still be answered, and well short of the time service managers wait before killing the server.
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(10);
 
 fn main() -> Result<(), Box<dyn error::Error>>{
-    let args: Vec<String> = env::args().collect();
-    let is_forward_mode = args.len() >= 3 && args[1] == "--resolver".to_string();
-
-    let forward_conn: Option<UdpSocket> = if is_forward_mode {
-        let forward_addr = &args[2];
-        println!("Forwarding queries to {}", forward_addr);
-        let socket = UdpSocket::bind("0.0.0.0:0")?;
-        socket.connect(forward_addr)?;
-        Some(socket)
-    } else {
-        println!("Running in resolve mode");
-        None
+    let config = match config::parse_args(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}\n{}", e, config::USAGE);
+            process::exit(2);
+        },
     };
 
-    println!("Server running on 127.0.0.1:2053");
-    let udp_socket = UdpSocket::bind("127.0.0.1:2053").expect("Failed to bind to address");
-    let mut buf = [0; 512];
+    match &config.resolver {
+        Some(forward_addr) => println!("Forwarding queries to {}", forward_addr),
+        None => println!("Running in resolve mode"),
+    }
 
-    loop {
-        match udp_socket.recv_from(&mut buf) {
-            Ok((size, source)) => {
-                let data: Vec<u8> = buf[..size].to_vec();
-                let mut response: Vec<u8> = Vec::new();
-
-                match &forward_conn {
-                    Some(c) => {
-                        match forward_request(data, c) {
-                            Ok(bytes) => response = bytes,
-                            Err(e) => eprintln!("failed to forward request: {}", e),
-                        }
-                    },
-
-                    None => {
-                        match resolve_request(data) {
-                            Ok(bytes) => response = bytes,
-                            Err(e) => eprintln!("failed to resolve request: {}", e),
-                        }
-                    }
-                }
+    // Every socket is bound before any are served, so a bad address stops the server before it answers anything
+    let mut udp_sockets: Vec<UdpSocket> = Vec::new();
+    let mut tcp_listeners: Vec<TcpListener> = Vec::new();
+    for addr in config.listen.iter() {
+        let bound = UdpSocket::bind(addr).and_then(|udp| Ok((udp, TcpListener::bind(addr)?)));
 
-                udp_socket
-                    .send_to(&response, source)
-                    .expect("Failed to send response");
-            }
+        match bound {
+            Ok((udp, tcp)) => {
+                udp_sockets.push(udp);
+                tcp_listeners.push(tcp);
+            },
             Err(e) => {
-                eprintln!("Error receiving data: {}", e);
-                break;
-            }
+                eprintln!("failed to listen on {}: {}", addr, e);
+                process::exit(1);
+            },
         }
     }
 
+    let server = Arc::new(Server::new(config)?);
+    let errors = Arc::new(ErrorCounter::new());
+
+    let shutdown = Arc::new(Shutdown::new());
+    let udp_addrs = udp_sockets.iter().map(|udp| udp.local_addr()).collect::<io::Result<Vec<SocketAddr>>>()?;
+    let tcp_addrs = tcp_listeners.iter().map(|tcp| tcp.local_addr()).collect::<io::Result<Vec<SocketAddr>>>()?;
+    let signals = Signals::new([SIGTERM, SIGINT])?;
+    {
+        let shutdown = Arc::clone(&shutdown);
+        thread::spawn(move || handle_signals(signals, &shutdown, &udp_addrs, &tcp_addrs));
+    }
+
+    let cpus = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let pool = WorkerPool::new(cpus * WORKERS_PER_CPU, UDP_QUEUE_LEN);
+
+    // Returns once the server has been asked to stop and every TCP connection has finished
+    thread::scope(|s| {
+        for socket in udp_sockets {
+            let (server, pool, errors, shutdown) = (&server, &pool, &errors, &shutdown);
+            s.spawn(move || {
+                if let Err(e) = serve_udp(Arc::new(socket), server, pool, errors, shutdown) {
+                    exit_on_socket_error("UDP socket failed", e);
+                }
+            });
+        }
+
+        for listener in tcp_listeners {
+            let (server, errors, shutdown) = (&server, &errors, &shutdown);
+            s.spawn(move || {
+                if let Err(e) = serve_tcp(listener, server, errors, shutdown) {
+                    exit_on_socket_error("TCP listener failed", e);
+                }
+            });
+        }
+    });
+
+    // Waits for the UDP queries that were already queued to be answered
+    drop(pool);
+
+    println!("Server stopped, after carrying on from {} socket errors", errors.count());
+    flush_logs();
+
     Ok(())
 }
 
-fn forward_request(data: Vec<u8>, forward_conn: &UdpSocket) -> Result<Vec<u8>, anyhow::Error>{
-    let mut msg = parse_message(&data);
-    let mut all_answers: Vec<ResourceRecord> = Vec::new();
+// Asks the server to stop on the first SIGTERM or SIGINT. If the queries being answered don't finish by the deadline, or another
+// signal arrives, the server stops without waiting for them.
+fn handle_signals(mut signals: Signals, shutdown: &Shutdown, udp_addrs: &[SocketAddr], tcp_addrs: &[SocketAddr]) {
+    let mut signals = signals.forever();
 
-    for q in msg.questions.iter() {
-        let mut header = msg.header;
-        header.qdcount = 1;
+    if signals.next().is_none() {
+        return;
+    }
 
-        let questions = vec![DNSQuestion{
-            qname: q.qname.to_owned(),
-            qtype: q.qtype,
-            qclass: q.qclass,
-        }];
+    println!("Shutting down, finishing the queries already received");
+    shutdown.request(udp_addrs, tcp_addrs);
 
-        let answers: Vec<ResourceRecord> = Vec::new();
+    thread::spawn(|| {
+        thread::sleep(SHUTDOWN_DEADLINE);
+        eprintln!("queries were still being answered after {:?}, stopping anyway", SHUTDOWN_DEADLINE);
+        flush_logs();
+        process::exit(0);
+    });
 
-        let msg_data = build_message(DNSMessage { header, questions, answers});
+    if signals.next().is_some() {
+        eprintln!("received another signal, stopping without finishing the queries being answered");
+        flush_logs();
+        process::exit(1);
+    }
+}
 
-        match forward_conn.send(&msg_data) {
-            Ok(_) => (),
-            Err(e) => return Err(anyhow!("failed to send data to forward server: {e}",)),
-        };
+// Logs go to stdout and stderr, which aren't flushed when the server stops with process::exit
+fn flush_logs() {
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+}
 
-        let mut buf = [0; 512];
-        let bytes_received = forward_conn.recv(&mut buf)?;
-        let response_data: Vec<u8> = buf[..bytes_received].to_vec();
+// A broken socket leaves some clients without answers, so the server stops with an error instead of carrying on half working, and
+// whatever runs it can start it again
+fn exit_on_socket_error(context: &str, e: io::Error) -> ! {
+    eprintln!("{}: {}", context, e);
+    process::exit(1);
+}
+
+// Hands the queries that arrive on a UDP socket to the worker pool. Returns once the server is shutting down, or with an error if
+// the socket stops working.
+fn serve_udp(udp_socket: Arc<UdpSocket>, server: &Arc<Server>, pool: &WorkerPool, errors: &Arc<ErrorCounter>, shutdown: &Shutdown) -> io::Result<()> {
+    if let Ok(addr) = udp_socket.local_addr() {
+        println!("Server running on {} (UDP)", addr);
+    }
+
+    let mut buf = vec![0; MAX_MESSAGE_LEN];
 
-        let mut response = parse_message(&response_data);
+    loop {
+        let received = udp_socket.recv_from(&mut buf);
 
-        // If there's an empty response, return an empty answer
-        if response.header.ancount == 0 {
-            response.answers = Vec::new();
+        // Queries that arrive once the server is shutting down aren't answered, and the clients will retry with another server
+        if shutdown.is_requested() {
+            return Ok(());
         }
 
-        all_answers.extend_from_slice(&response.answers);
-    }
+        match received {
+            Ok((size, source)) => {
+                let data = buf[..size].to_vec();
+                let (server, udp_socket, errors) = (Arc::clone(server), Arc::clone(&udp_socket), Arc::clone(errors));
+
+                let queued = pool.try_execute(move || {
+                    let Some(response) = server.handle_udp(&data, source) else {
+                        return;
+                    };
 
-    msg.header.qr = types::QR::Response;
-    msg.answers = all_answers;
-    msg.header.ancount = msg.answers.len() as u16;
+                    socket::send_response(&udp_socket, &response, source, &errors);
+                });
 
-    Ok(build_message(msg))
+                if !queued {
+                    eprintln!("dropped query from {}, all workers are busy", source);
+                }
+            }
+            Err(e) if socket::is_transient(&e) => errors.record("failed to receive query", &e),
+            Err(e) => return Err(e),
+        }
+    }
 }
 
-fn resolve_request(data: Vec<u8>) -> Result<Vec<u8>, anyhow::Error> {
-    let mut msg = parse_message(&data);
-    msg.header.qr = types::QR::Response;
-    msg.header.aa = false;
-    msg.header.tc = false;
-    msg.header.ra = false;
-    msg.header.z = 0;
-
-    for q in msg.questions.iter_mut() {
-        q.qtype = types::RecordType::A;
-        q.qclass = types::ClassType::IN;
-
-        let answer = ResourceRecord {
-            name: q.qname.to_owned(),
-            record_type: types::RecordType::A,
-            class: types::ClassType::IN,
-            ttl: 0,
-            rdlength: 4,
-            rdata: vec![192, 168, 0, 6],
+// Accepts TCP connections and serves each one on its own thread. Returns once the server is shutting down and every connection has
+// finished, or with an error if the listener stops working.
+fn serve_tcp(listener: TcpListener, server: &Server, errors: &ErrorCounter, shutdown: &Shutdown) -> io::Result<()> {
+    if let Ok(addr) = listener.local_addr() {
+        println!("Server running on {} (TCP)", addr);
+    }
+
+    thread::scope(|s| loop {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if socket::is_transient(&e) => {
+                errors.record("failed to accept TCP connection", &e);
+                thread::sleep(ACCEPT_RETRY_DELAY);
+                continue;
+            },
+            Err(e) => return Err(e),
         };
 
-        msg.answers.push(answer);
-    }
+        if shutdown.is_requested() {
+            return Ok(());
+        }
 
-    msg.header.ancount = msg.answers.len() as u16;
+        // Connections over the limit are closed straight away, so the client can try again later or somewhere else
+        let connection = match shutdown.track(&stream, MAX_TCP_CONNECTIONS) {
+            Ok(Some(connection)) => connection,
+            Ok(None) => continue,
+            Err(e) => {
+                errors.record("failed to track TCP connection", &e);
+                continue;
+            },
+        };
+
+        // Problems with one connection never affect the listener
+        s.spawn(move || {
+            match serve_tcp_connection(stream, server) {
+                Ok(()) => (),
+                Err(_) if shutdown.is_requested() => (), // Closed in the middle of a query
+                Err(e) if socket::is_transient(&e) => errors.record("TCP connection failed", &e),
+                Err(e) => eprintln!("TCP connection failed: {}", e),
+            }
+
+            drop(connection);
+        });
+    })
+}
+
+// Answers the queries on a TCP connection until the client closes it, stops sending them, or the server shuts down. Clients can send several queries
+// without waiting for answers, and each one is answered as soon as it's ready, so a slow query doesn't hold up the ones after it
+// (RFC 7766 section 6.2.1.1).
+fn serve_tcp_connection(mut stream: TcpStream, server: &Server) -> io::Result<()> {
+    let source = stream.peer_addr()?;
+    stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+    stream.set_write_timeout(Some(TCP_WRITE_TIMEOUT))?;
+
+    // Responses are written whole while holding the lock, so ones from different queries can't get mixed together
+    let writer = Mutex::new(stream.try_clone()?);
+    let in_flight = AtomicUsize::new(0);
+
+    // After a failed write, part of a response may have been sent, so nothing more can be written. Shutting the connection down
+    // makes the loop's next read end it, and makes the writes still to come fail straight away.
+    let answer = |query: Vec<u8>| {
+        if let Some(response) = server.handle_tcp(&query, source) {
+            let mut writer = writer.lock().unwrap();
+            if tcp::write_message(&mut *writer, &response).is_err() {
+                let _ = writer.shutdown(net::Shutdown::Both);
+            }
+        }
+    };
+
+    thread::scope(|s| loop {
+        let query = match tcp::read_message(&mut stream) {
+            Ok(Some(query)) => query,
+            Ok(None) => return Ok(()),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => return Ok(()), // Idle for too long
+            Err(e) => return Err(e),
+        };
+
+        if in_flight.load(Ordering::SeqCst) >= MAX_TCP_QUERIES_IN_FLIGHT {
+            answer(query);
+            continue;
+        }
 
-    Ok(build_message(msg))
+        in_flight.fetch_add(1, Ordering::SeqCst);
+        let in_flight = &in_flight;
+        s.spawn(move || {
+            answer(query);
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+        });
+    })
 }
@@ -1,26 +1,203 @@
-use crate::types::{ClassType, DNSHeader, DNSMessage, DNSQuestion, Opcode, RecordType, ResourceRecord, QR, RCODE};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-pub fn parse_message(data: &Vec<u8>) -> DNSMessage {
-    let header_data: [u8; 12] = data[0..12].try_into().unwrap();
-    let non_header_data: Vec<u8>= data[12..].to_vec();
+use thiserror::Error;
+
+use crate::types::{
+    ClassType, DNSHeader, DNSMessage, DNSQuestion, Edns, EdnsOption, ExtendedErrorCode, Name, Opcode, RData, RecordType, ResourceRecord,
+    OPTION_CLIENT_SUBNET, OPTION_COOKIE, OPTION_EXTENDED_ERROR, QR, RCODE,
+};
+use crate::view::{MessageView, Section};
+
+pub(crate) const HEADER_LEN: usize = 12;
+
+// Names are limited to 255 bytes in wire format, including the length bytes and the null termination byte
+const MAX_NAME_LEN: usize = 255;
+
+// Legitimate encoders point at the longest suffix they've already written, so a handful of hops is all a name should ever need
+const MAX_POINTERS: usize = 16;
+
+/// Reasons a datagram could not be decoded. Every variant carries the offset
+/// (from the start of the message) of the byte where decoding went wrong.
+#[derive(Debug, Error, PartialEq, Eq, Clone, Copy)]
+pub enum ParseError {
+    #[error("message is {offset} bytes long, too short to hold a header")]
+    TruncatedHeader { offset: usize },
+
+    #[error("domain name runs past the end of the message at offset {offset}")]
+    TruncatedName { offset: usize },
+
+    #[error("message ends in the middle of a field at offset {offset}")]
+    Truncated { offset: usize },
+
+    #[error("label at offset {offset} is {length} bytes long, but labels are limited to 63 bytes")]
+    LabelTooLong { offset: usize, length: u8 },
+
+    #[error("domain name at offset {offset} is longer than 255 bytes")]
+    NameTooLong { offset: usize },
+
+    #[error("compression pointer at offset {offset} doesn't point to an earlier name")]
+    BadPointer { offset: usize },
+
+    #[error("compression pointer at offset {offset} is more than {MAX_POINTERS} pointers deep")]
+    TooManyPointers { offset: usize },
+
+    #[error("record data at offset {offset} is {rdlength} bytes long, but only {remaining} bytes remain")]
+    RdlengthOverrun { offset: usize, rdlength: u16, remaining: usize },
+
+    #[error("{record_type} record data at offset {offset} is malformed")]
+    BadRdata { offset: usize, record_type: RecordType },
+
+    #[error("OPT record at offset {offset} is either a second OPT record or not owned by the root")]
+    BadOpt { offset: usize },
+
+    #[error("unexpected data after the last record at offset {offset}")]
+    TrailingGarbage { offset: usize },
+}
+
+// Reads through a message front to back. The whole message is always kept around, since compression pointers are offsets from its start.
+pub(crate) struct Cursor<'a> {
+    pub(crate) data: &'a [u8],
+    pub(crate) idx: usize,
+}
+
+impl<'a> Cursor<'a> {
+    pub(crate) fn new(data: &'a [u8], idx: usize) -> Self {
+        Cursor { data, idx }
+    }
+
+    pub(crate) fn read_u8(&mut self) -> Result<u8, ParseError> {
+        let bytes = self.read_bytes(1)?;
+        Ok(bytes[0])
+    }
+
+    pub(crate) fn read_u16(&mut self) -> Result<u16, ParseError> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub(crate) fn read_u32(&mut self) -> Result<u32, ParseError> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub(crate) fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], ParseError> {
+        match self.data.get(self.idx..self.idx + len) {
+            Some(bytes) => {
+                self.idx += len;
+                Ok(bytes)
+            },
+            None => Err(ParseError::Truncated { offset: self.idx }),
+        }
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        self.data.len() - self.idx
+    }
+}
+
+/// Decodes a whole message. The OPT record, if there is one, is taken out of the additional section and put in `edns`.
+pub fn parse_message(data: &[u8]) -> Result<DNSMessage, ParseError> {
+    let header_data: [u8; HEADER_LEN] = match data.get(0..HEADER_LEN) {
+        Some(bytes) => bytes.try_into().unwrap(),
+        None => return Err(ParseError::TruncatedHeader { offset: data.len() }),
+    };
 
     let mut header = parse_header(&header_data);
-    let (questions, answer_idx) = parse_question(&non_header_data, header.qdcount);
-    let (answers, _current_byte) = parse_record(&non_header_data, header.ancount, answer_idx);
 
-	// This server only handles standard queries, so we need to indicate that other request types aren't handled
-    if header.opcode != Opcode::QUERY {
-        header.rcode = RCODE::NotImplemented;
+    let mut cursor = Cursor::new(data, HEADER_LEN);
+    let questions = parse_question(&mut cursor, header.qdcount)?;
+    let answers = parse_record(&mut cursor, header.ancount)?;
+    let authorities = parse_record(&mut cursor, header.nscount)?;
+    let (additionals, edns) = parse_additionals(&mut cursor, &mut header)?;
+
+    if cursor.remaining() != 0 {
+        return Err(ParseError::TrailingGarbage { offset: cursor.idx });
     }
 
-    DNSMessage {
+    Ok(DNSMessage {
         header,
         questions,
         answers,
+        authorities,
+        additionals,
+        edns,
+    })
+}
+
+/// Decodes what a server needs to answer a query: the header, the questions and the OPT record. The other records are checked
+/// with a `MessageView` but not decoded, since a query is never answered from them, so a query costs much less to decode than
+/// with `parse_message`. The message that's returned has no answer, authority or additional records, and its header counts
+/// agree.
+pub fn parse_query(data: &[u8]) -> Result<DNSMessage, ParseError> {
+    let view = MessageView::new(data)?;
+    let mut header = view.header();
+    let questions = view.questions().map(|q| q.map(|q| q.to_question())).collect::<Result<Vec<_>, _>>()?;
+
+    let mut edns: Option<Edns> = None;
+    let mut records = view.records();
+
+    loop {
+        let record_start = records.offset();
+        let record = match records.next() {
+            Some(record) => record?,
+            None => break,
+        };
+
+        if record.section != Section::Additional || record.record_type != RecordType::OPT {
+            continue;
+        }
+
+        // A message can only have one OPT record, and it always belongs to the root (RFC 6891 section 6.1.1)
+        if edns.is_some() || record.name.labels().next().is_some() {
+            return Err(ParseError::BadOpt { offset: record_start });
+        }
+
+        edns = Some(decode_opt(&mut header, record.class, record.ttl, &mut record.rdata_cursor())?);
     }
+
+    if records.offset() != data.len() {
+        return Err(ParseError::TrailingGarbage { offset: records.offset() });
+    }
+
+    header.ancount = 0;
+    header.nscount = 0;
+    header.arcount = 0;
+
+    Ok(DNSMessage {
+        header,
+        questions,
+        answers: Vec::new(),
+        authorities: Vec::new(),
+        additionals: Vec::new(),
+        edns,
+    })
+}
+
+/// The parts of a malformed message that could still be decoded
+#[derive(PartialEq, Eq, Debug)]
+pub struct PartialMessage {
+    pub id: u16,
+    pub header: Option<DNSHeader>,
+    pub questions: Vec<DNSQuestion>,
+}
+
+/// Salvages the ID, header and question section of a message that `parse_message` or `parse_query` rejected, so that it can still
+/// be answered. Returns `None` when the message is too short to even hold an ID.
+pub fn parse_partial(data: &[u8]) -> Option<PartialMessage> {
+    let id = u16::from_be_bytes([*data.first()?, *data.get(1)?]);
+
+    let view = match MessageView::new(data) {
+        Ok(view) => view,
+        Err(_) => return Some(PartialMessage { id, header: None, questions: Vec::new() }),
+    };
+
+    // The questions are only worth echoing if all of them could be decoded
+    let questions = view.questions().map(|q| q.map(|q| q.to_question())).collect::<Result<_, _>>().unwrap_or_default();
+
+    Some(PartialMessage { id, header: Some(view.header()), questions })
 }
 
-fn parse_header(data: &[u8; 12]) -> DNSHeader {
+pub(crate) fn parse_header(data: &[u8; 12]) -> DNSHeader {
     let id = u16::from_be_bytes([data[0], data[1]]);
 
     let byte_two = data[2]; // QR, OPCODE, AA, TC, RD
@@ -30,171 +207,397 @@ fn parse_header(data: &[u8; 12]) -> DNSHeader {
         _ => QR::Response,
     };
 
-    let opcode = match (byte_two & 0b0111_1000) >> 3 {
-        0 => Opcode::QUERY,
-        1 => Opcode::IQUERY,
-        2 => Opcode::STATUS,
-        _ => Opcode::Reserved,
-    };
+    let opcode = Opcode::from((byte_two & 0b0111_1000) >> 3);
 
-    let aa = match byte_two & 0b0000_0100 {
-        0 => false,
-        _ => true,
-    };
+    let aa = byte_two & 0b0000_0100 != 0;
+    let tc = byte_two & 0b0000_0010 != 0;
+    let rd = byte_two & 0b0000_0001 != 0;
 
-    let tc = match byte_two & 0b0000_0010 {
-        0 => false,
-        _ => true,
-    };
+    let byte_three= data[3]; // RA, Z, AD, CD, RCODE
 
-    let rd = match byte_two & 0b0000_0001 {
-        0 => false,
-        _ => true,
-    };
+    let ra = byte_three & 0b1000_0000 != 0;
 
-    let byte_three= data[3]; // RA, Z, RCODE
+    let z = (byte_three & 0b0100_0000) >> 6;
+    let ad = byte_three & 0b0010_0000 != 0;
+    let cd = byte_three & 0b0001_0000 != 0;
 
-    let ra = match byte_three & 0b1000_0000 {
-        0 => false,
-        _ => true,
-    };
-
-    let z = (byte_three & 0b0111_0000) >> 4;
-
-    let rcode = match byte_three & 0b0000_1111 {
-        0 => RCODE::NoError,
-        1 => RCODE::FormatError,
-        2 => RCODE::ServerFailure,
-        3 => RCODE::NameError,
-        4 => RCODE::NotImplemented,
-        5 => RCODE::Refused,
-        _ => RCODE::Reserved,
-    };
+    let rcode = RCODE::from((byte_three & 0b0000_1111) as u16);
 
     let qdcount = u16::from_be_bytes([data[4], data[5]]);
-    let ancount = u16::from_be_bytes([data[8], data[7]]);
+    let ancount = u16::from_be_bytes([data[6], data[7]]);
     let nscount = u16::from_be_bytes([data[8], data[9]]);
     let arcount = u16::from_be_bytes([data[10], data[11]]);
 
     DNSHeader{
-        id, qr, opcode, aa, tc, rd, ra, z, rcode,
+        id, qr, opcode, aa, tc, rd, ra, z, ad, cd, rcode,
         qdcount, ancount, nscount, arcount,
     }
 }
 
-fn parse_question(data: &Vec<u8>, num_questions: u16) -> (Vec<DNSQuestion>, usize){
+
+fn parse_question(cursor: &mut Cursor, num_questions: u16) -> Result<Vec<DNSQuestion>, ParseError> {
     let mut questions: Vec<DNSQuestion> = Vec::new();
-    let mut current_byte = 0;
 
     for _i in 0..num_questions {
-        let (qname, start) = parse_domain(data, current_byte);
+        let qname = parse_domain(cursor)?;
+        let qtype = RecordType::from(cursor.read_u16()?);
+        let qclass = ClassType::from(cursor.read_u16()?);
 
-        current_byte = start + 1; // Increment to start of QTYPE
+        questions.push(DNSQuestion {qname, qtype, qclass});
+    }
 
-        let qtype = match u16::from_be_bytes([data[current_byte], data[current_byte + 1]]) {
-            1 => RecordType::A,
-            _ => RecordType::Other,
-        };
+    Ok(questions)
+}
 
-        current_byte += 2; // Increment to start of QCLASS
+fn parse_record(cursor: &mut Cursor, num_records: u16) -> Result<Vec<ResourceRecord>, ParseError> {
+    let mut records: Vec<ResourceRecord> = Vec::new();
 
-        let qclass = match u16::from_be_bytes([data[current_byte], data[current_byte + 1]]) {
-            1 => ClassType::IN,
-            _ => ClassType::Other,
-        };
+    for _i in 0..num_records {
+        let name = parse_domain(cursor)?;
+        let record_type = RecordType::from(cursor.read_u16()?);
+        let class = ClassType::from(cursor.read_u16()?);
+        let ttl = cursor.read_u32()?;
+        let rdlength = cursor.read_u16()?;
+
+        if rdlength as usize > cursor.remaining() {
+            return Err(ParseError::RdlengthOverrun {
+                offset: cursor.idx,
+                rdlength,
+                remaining: cursor.remaining(),
+            });
+        }
 
-        current_byte += 2; // Final increment to byte after current question
+        // Names in the RDATA can point anywhere before them, but nothing in the RDATA can run past RDLENGTH
+        let rdata_end = cursor.idx + rdlength as usize;
+        let mut rdata_cursor = Cursor::new(&cursor.data[..rdata_end], cursor.idx);
+        let rdata = parse_rdata(&mut rdata_cursor, record_type, class)?;
 
-        questions.push(DNSQuestion {qname, qtype, qclass});
-    }
+        cursor.idx = rdata_end; // Skip to byte after current record
 
-    (questions, current_byte)
+        records.push(ResourceRecord{name, record_type, class, ttl, rdata});
+    }
 
+    Ok(records)
 }
 
-fn parse_record(data: &Vec<u8>, num_answers: u16, current_byte: usize) -> (Vec<ResourceRecord>, usize){
-    let mut records: Vec<ResourceRecord> = Vec::new();
-    let mut current_byte = current_byte;
+// Reads the additional section, taking out the OPT record if there is one. ARCOUNT is adjusted so it only counts the records that
+// are left, and the extended RCODE bits from the OPT record are added to the header's RCODE.
+fn parse_additionals(cursor: &mut Cursor, header: &mut DNSHeader) -> Result<(Vec<ResourceRecord>, Option<Edns>), ParseError> {
+    let mut additionals: Vec<ResourceRecord> = Vec::new();
+    let mut edns: Option<Edns> = None;
 
-    for _i in 0..num_answers {
-        let (name, start) = parse_domain(data, current_byte);
+    for _i in 0..header.arcount {
+        let record_start = cursor.idx;
+        let record = parse_record(cursor, 1)?.remove(0);
 
-        current_byte = start + 1; // Increment to start of TYPE
+        if record.record_type != RecordType::OPT {
+            additionals.push(record);
+            continue;
+        }
+
+        // A message can only have one OPT record, and it always belongs to the root (RFC 6891 section 6.1.1)
+        if edns.is_some() || !record.name.is_root() {
+            return Err(ParseError::BadOpt { offset: record_start });
+        }
 
-        let record_type = match u16::from_be_bytes([data[current_byte], data[current_byte+1]]) {
-            1 => RecordType::A,
-            _ => RecordType::Other,
+        // OPT has no RData variant, so its RDATA is still the raw bytes right before the cursor
+        let rdlength = match &record.rdata {
+            RData::Unknown { bytes, .. } => bytes.len(),
+            _ => 0,
         };
+        let mut rdata = Cursor::new(&cursor.data[..cursor.idx], cursor.idx - rdlength);
 
-        current_byte +=2; // Increment to start of CLASS
+        edns = Some(decode_opt(header, record.class, record.ttl, &mut rdata)?);
+    }
 
-        let class = match u16::from_be_bytes([data[current_byte], data[current_byte+1]]) {
-            1 => ClassType::IN,
-            _ => ClassType::Other,
-        };
+    header.arcount = additionals.len() as u16;
+
+    Ok((additionals, edns))
+}
 
-        current_byte += 2; // Increment to start of TTL
+// Decodes the fields of an OPT record, adding the extended RCODE bits to the header's RCODE
+fn decode_opt(header: &mut DNSHeader, class: ClassType, ttl: u32, rdata: &mut Cursor) -> Result<Edns, ParseError> {
+    let options = parse_edns_options(rdata)?;
+
+    // The TTL is made up of the upper 8 bits of the extended RCODE, the version, the DO bit and 15 reserved bits
+    let upper_bits = (ttl >> 24) as u16;
+    header.rcode = RCODE::from((upper_bits << 4) | u16::from(header.rcode));
+
+    Ok(Edns {
+        udp_payload_size: u16::from(class),
+        version: (ttl >> 16) as u8,
+        dnssec_ok: ttl & 0x8000 != 0,
+        z: (ttl & 0x7FFF) as u16,
+        options,
+    })
+}
 
-        let ttl = u32::from_be_bytes([data[current_byte], data[current_byte+1], data[current_byte+2], data[current_byte+3]]);
+// Reads the {code, length, data} triples that make up the RDATA of an OPT record
+fn parse_edns_options(cursor: &mut Cursor) -> Result<Vec<EdnsOption>, ParseError> {
+    let mut options: Vec<EdnsOption> = Vec::new();
 
-        current_byte += 4; // Increment to start of RDLENGTH
+    while cursor.remaining() > 0 {
+        let bad_option = ParseError::BadRdata { offset: cursor.idx, record_type: RecordType::OPT };
 
-        let rdlength = u16::from_be_bytes([data[current_byte], data[current_byte+1]]);
+        match decode_edns_option(cursor) {
+            Ok(option) => options.push(option),
+            Err(_) => return Err(bad_option),
+        }
+    }
 
-        current_byte += 2; // Increment to start of RDATA
+    Ok(options)
+}
 
-        let rdata: Vec<u8> = data[current_byte..current_byte+rdlength as usize].to_vec();
+fn decode_edns_option(cursor: &mut Cursor) -> Result<EdnsOption, ParseError> {
+    let code = cursor.read_u16()?;
+    let length = cursor.read_u16()?;
+    let data = cursor.read_bytes(length as usize)?;
+
+    // Each option is decoded on its own, so it can't run into the next one
+    let mut option_cursor = Cursor::new(&cursor.data[..cursor.idx], cursor.idx - data.len());
+
+    let option = match code {
+        OPTION_CLIENT_SUBNET => decode_client_subnet(&mut option_cursor)?,
+        OPTION_COOKIE => decode_cookie(&mut option_cursor)?,
+        OPTION_EXTENDED_ERROR => EdnsOption::ExtendedError {
+            info_code: ExtendedErrorCode::from(option_cursor.read_u16()?),
+            // The text is only for people to read, so it's not worth rejecting the message over bad UTF-8
+            extra_text: String::from_utf8_lossy(option_cursor.read_bytes(option_cursor.remaining())?).into_owned(),
+        },
+        _ => EdnsOption::Unknown { code, data: data.to_vec() },
+    };
+
+    Ok(option)
+}
 
-        current_byte += rdlength as usize; // Increment to byte after current record
+// The address only takes up as many bytes as its source prefix needs, and any bits past the prefix have to be zero
+// (RFC 7871 section 6)
+fn decode_client_subnet(cursor: &mut Cursor) -> Result<EdnsOption, ParseError> {
+    let bad_option = ParseError::BadRdata { offset: cursor.idx, record_type: RecordType::OPT };
 
-        records.push(ResourceRecord{name, record_type, class, ttl, rdlength, rdata});
+    let family = cursor.read_u16()?;
+    let source_prefix = cursor.read_u8()?;
+    let scope_prefix = cursor.read_u8()?;
 
+    let address_len = match family {
+        1 => 4,
+        2 => 16,
+        _ => return Err(bad_option),
+    };
+
+    if source_prefix as usize > address_len * 8 || scope_prefix as usize > address_len * 8 {
+        return Err(bad_option);
     }
 
-    (records, current_byte)
+    let prefix_bytes = cursor.read_bytes(cursor.remaining())?;
+    if prefix_bytes.len() != (source_prefix as usize).div_ceil(8) {
+        return Err(bad_option);
+    }
 
+    // Only the last byte can have bits past the prefix
+    let unused_bits = prefix_bytes.len() * 8 - source_prefix as usize;
+    if prefix_bytes.last().is_some_and(|b| b & ((1 << unused_bits) - 1) != 0) {
+        return Err(bad_option);
+    }
+
+    let mut octets = [0; 16];
+    octets[..prefix_bytes.len()].copy_from_slice(prefix_bytes);
+
+    let address = match family {
+        1 => IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3])),
+        _ => IpAddr::V6(Ipv6Addr::from(octets)),
+    };
+
+    Ok(EdnsOption::ClientSubnet { source_prefix, scope_prefix, address })
 }
 
-fn parse_domain(data: &Vec<u8>, start_idx: usize) -> (Vec<u8>, usize) {
-    let mut result: Vec<u8> = Vec::new();
-    let mut byte_is_content = false;
-    let mut start_idx = start_idx;
+// An 8 byte client cookie, optionally followed by an 8 to 32 byte server cookie (RFC 7873 section 4)
+fn decode_cookie(cursor: &mut Cursor) -> Result<EdnsOption, ParseError> {
+    let bad_option = ParseError::BadRdata { offset: cursor.idx, record_type: RecordType::OPT };
 
-    loop {
-        let mut content_length: u8 = 0;
+    let client: [u8; 8] = cursor.read_bytes(8)?.try_into().unwrap();
+    let server = cursor.read_bytes(cursor.remaining())?;
+
+    if !server.is_empty() && !(8..=32).contains(&server.len()) {
+        return Err(bad_option);
+    }
+
+    Ok(EdnsOption::Cookie { client, server: server.to_vec() })
+}
 
-        if !byte_is_content {
-            if data[start_idx] & 0b1100_0000 == 0b1100_0000 {
-                let pointer = u16::from_be_bytes([data[start_idx], data[start_idx + 1]]) & 0b0011_1111_1111_1111;
-                let offset = (pointer - 12) as usize; // Since we sliced off the header data, we need to adjust the pointerOffset so that it is pointing to the correct data
-                let (decompressed_data, _) = parse_domain(data, offset);
+// Decodes the RDATA that starts at the cursor and runs to the end of its data
+pub(crate) fn parse_rdata(cursor: &mut Cursor, record_type: RecordType, class: ClassType) -> Result<RData, ParseError> {
+    let bad_rdata = ParseError::BadRdata { offset: cursor.idx, record_type };
 
-                result.extend(decompressed_data);
-                start_idx += 1; // Increment to the 2nd (last) byte of the pointer
-                break
+    let rdata = match decode_rdata(cursor, record_type, class) {
+        Ok(rdata) if cursor.remaining() == 0 => rdata,
+        Ok(_) => return Err(bad_rdata), // The RDATA was shorter than RDLENGTH claimed
+        Err(ParseError::Truncated { .. }) | Err(ParseError::TruncatedName { .. }) => return Err(bad_rdata),
+        Err(e) => return Err(e),
+    };
+
+    Ok(rdata)
+}
+
+fn decode_rdata(cursor: &mut Cursor, record_type: RecordType, class: ClassType) -> Result<RData, ParseError> {
+    let rdata = match (record_type, class) {
+        // The layout of address records depends on the class, so only the internet ones get decoded
+        (RecordType::A, ClassType::IN) => {
+            let octets: [u8; 4] = cursor.read_bytes(4)?.try_into().unwrap();
+            RData::A(Ipv4Addr::from(octets))
+        },
+
+        (RecordType::AAAA, ClassType::IN) => {
+            let octets: [u8; 16] = cursor.read_bytes(16)?.try_into().unwrap();
+            RData::AAAA(Ipv6Addr::from(octets))
+        },
+
+        (RecordType::NS, _) => RData::NS(parse_domain(cursor)?),
+        (RecordType::CNAME, _) => RData::CNAME(parse_domain(cursor)?),
+        (RecordType::PTR, _) => RData::PTR(parse_domain(cursor)?),
+        (RecordType::DNAME, _) => RData::DNAME(parse_domain(cursor)?),
+
+        (RecordType::MX, _) => RData::MX {
+            preference: cursor.read_u16()?,
+            exchange: parse_domain(cursor)?,
+        },
+
+        (RecordType::SOA, _) => RData::SOA {
+            mname: parse_domain(cursor)?,
+            rname: parse_domain(cursor)?,
+            serial: cursor.read_u32()?,
+            refresh: cursor.read_u32()?,
+            retry: cursor.read_u32()?,
+            expire: cursor.read_u32()?,
+            minimum: cursor.read_u32()?,
+        },
+
+        (RecordType::TXT, _) => {
+            let mut strings: Vec<Vec<u8>> = Vec::new();
+
+            while cursor.remaining() > 0 {
+                strings.push(parse_character_string(cursor)?);
             }
 
-            content_length = data[start_idx];
+            RData::TXT(strings)
+        },
+
+        (RecordType::SRV, _) => RData::SRV {
+            priority: cursor.read_u16()?,
+            weight: cursor.read_u16()?,
+            port: cursor.read_u16()?,
+            target: parse_domain(cursor)?,
+        },
+
+        (RecordType::CAA, _) => RData::CAA {
+            flags: cursor.read_u8()?,
+            tag: parse_character_string(cursor)?,
+            // The value isn't length-prefixed, it just takes up the rest of the RDATA
+            value: cursor.read_bytes(cursor.remaining())?.to_vec(),
+        },
+
+        (RecordType::NAPTR, _) => RData::NAPTR {
+            order: cursor.read_u16()?,
+            preference: cursor.read_u16()?,
+            flags: parse_character_string(cursor)?,
+            services: parse_character_string(cursor)?,
+            regexp: parse_character_string(cursor)?,
+            replacement: parse_domain(cursor)?,
+        },
+
+        _ => RData::Unknown {
+            rtype: record_type,
+            bytes: cursor.read_bytes(cursor.remaining())?.to_vec(),
+        },
+    };
+
+    Ok(rdata)
+}
+
+// Reads a length-prefixed <character-string>, returning its contents
+fn parse_character_string(cursor: &mut Cursor) -> Result<Vec<u8>, ParseError> {
+    let length = cursor.read_u8()?;
+    Ok(cursor.read_bytes(length as usize)?.to_vec())
+}
+
+// Reads a possibly compressed name. The cursor is left on the byte after the name as it appears at the cursor, i.e. after the null
+// termination byte or the first pointer.
+fn parse_domain(cursor: &mut Cursor) -> Result<Name, ParseError> {
+    let mut labels: Vec<&[u8]> = Vec::new();
+    let end = walk_name(cursor.data, cursor.idx, |label| labels.push(label))?;
+
+    // The limits were already checked while walking the name, where the offset of the name is known
+    let name = Name::from_labels(labels).map_err(|_| ParseError::NameTooLong { offset: cursor.idx })?;
+
+    cursor.idx = end;
+
+    Ok(name)
+}
 
-            result.push(content_length);
+// Follows a possibly compressed name that starts at `start`, checking it along the way and passing each of its labels to `on_label`.
+// Returns the offset of the byte after the name as it appears at `start`.
+pub(crate) fn walk_name<'a>(data: &'a [u8], start: usize, mut on_label: impl FnMut(&'a [u8])) -> Result<usize, ParseError> {
+    let mut name_len = 1; // Wire length of the labels so far, plus the null termination byte
+    let mut idx = start;
 
-            if content_length == 0 { // Null termination byte
-                break
-            } else {
-                start_idx += 1;
+    // Every pointer has to point before the labels that lead up to it, which guarantees that following them can't loop
+    let mut labels_start = start;
+    let mut resume_idx: Option<usize> = None;
+    let mut num_pointers = 0;
+
+    loop {
+        let content_length = match data.get(idx) {
+            Some(byte) => *byte,
+            None => return Err(ParseError::TruncatedName { offset: idx }),
+        };
+
+        if content_length & 0b1100_0000 == 0b1100_0000 {
+            let pointer = match data.get(idx + 1) {
+                Some(byte) => (u16::from_be_bytes([content_length, *byte]) & 0b0011_1111_1111_1111) as usize,
+                None => return Err(ParseError::TruncatedName { offset: idx + 1 }),
+            };
+
+            // Names can't live in the header, and forward or self-referencing pointers are never needed
+            if pointer < HEADER_LEN || pointer >= labels_start {
+                return Err(ParseError::BadPointer { offset: idx });
+            }
+
+            num_pointers += 1;
+            if num_pointers > MAX_POINTERS {
+                return Err(ParseError::TooManyPointers { offset: idx });
             }
+
+            // Only the first pointer is part of the name as it appears at the start
+            resume_idx.get_or_insert(idx + 2);
+
+            labels_start = pointer;
+            idx = pointer;
+            continue;
+        }
+
+        if content_length > 63 {
+            return Err(ParseError::LabelTooLong { offset: idx, length: content_length });
+        }
+
+        if content_length == 0 { // Null termination byte
+            idx += 1;
+            break
         }
 
-        let next_non_content_byte = start_idx + content_length as usize;
-        for idx in start_idx..next_non_content_byte {
-            result.push(data[idx]);
-            start_idx += 1;
+        name_len += 1 + content_length as usize;
+        if name_len > MAX_NAME_LEN {
+            return Err(ParseError::NameTooLong { offset: start });
         }
 
-        byte_is_content = false;
+        match data.get(idx + 1..idx + 1 + content_length as usize) {
+            Some(content) => on_label(content),
+            None => return Err(ParseError::TruncatedName { offset: data.len() }),
+        }
+
+        idx += 1 + content_length as usize;
     }
 
-    (result, start_idx)
+    Ok(resume_idx.unwrap_or(idx))
 }
 
 #[cfg(test)]
@@ -215,7 +618,7 @@ mod tests {
                 data: [
                     0b0000_0100, 0b1101_0010, // ID: 1234
                     0b1010_1010, // QR: 1, Opcode: 5, AA: 0, TC: 1, RD: 0,
-                    0b1101_0011, // RA: 1, Z: 5, RCODE: 3
+                    0b1101_0011, // RA: 1, Z: 1, AD: 0, CD: 1, RCODE: 3
                     0b0000_0000, 0b0100_0101, // QDCOUNT: 69
                     0b0000_0000, 0b0100_0110, // ANCOUNT: 70
                     0b0000_0000, 0b0100_0111, // NSCOUNT: 71
@@ -224,12 +627,48 @@ mod tests {
                 want: DNSHeader {
                     id: 1234,
                     qr: QR::Response,
-                    opcode: Opcode::Reserved,
-                    aa: false, tc: true, rd: false, ra: true, z: 5,
+                    opcode: Opcode::UPDATE,
+                    aa: false, tc: true, rd: false, ra: true, z: 1, ad: false, cd: true,
                     rcode: RCODE::NameError,
                     qdcount: 69, ancount: 70, nscount: 71, arcount: 72
                 }
-            }
+            },
+
+            Test {
+                label: "unassigned opcode and rcode".to_string(),
+                data: [
+                    0b0000_0100, 0b1101_0010, // ID: 1234
+                    0b0011_1000, // QR: 0, Opcode: 7, AA: 0, TC: 0, RD: 0,
+                    0b0000_1111, // RA: 0, Z: 0, AD: 0, CD: 0, RCODE: 15
+                    0, 0, 0, 0, 0, 0, 0, 0,
+                ],
+                want: DNSHeader {
+                    id: 1234,
+                    qr: QR::Query,
+                    opcode: Opcode::Unknown(7),
+                    aa: false, tc: false, rd: false, ra: false, z: 0, ad: false, cd: false,
+                    rcode: RCODE::Unknown(15),
+                    qdcount: 0, ancount: 0, nscount: 0, arcount: 0
+                }
+            },
+
+            Test {
+                label: "NOTIFY and YXDOMAIN".to_string(),
+                data: [
+                    0b0000_0100, 0b1101_0010, // ID: 1234
+                    0b1010_0100, // QR: 1, Opcode: 4, AA: 1, TC: 0, RD: 0,
+                    0b0000_0110, // RA: 0, Z: 0, AD: 0, CD: 0, RCODE: 6
+                    0, 0, 0, 0, 0, 0, 0, 0,
+                ],
+                want: DNSHeader {
+                    id: 1234,
+                    qr: QR::Response,
+                    opcode: Opcode::NOTIFY,
+                    aa: true, tc: false, rd: false, ra: false, z: 0, ad: false, cd: false,
+                    rcode: RCODE::YXDomain,
+                    qdcount: 0, ancount: 0, nscount: 0, arcount: 0
+                }
+            },
         ];
 
         for t in tests {
@@ -265,15 +704,15 @@ mod tests {
 
                 want_questions: vec![
                     DNSQuestion {
-                        qname: vec![
+                        qname: Name::from_wire(&[
                             0x07,
                             b'e', b'x', b'a', b'm', b'p', b'l', b'e',
                             0x03,
                             b'c', b'o', b'm',
                             0x00,
-                        ],
+                        ]).unwrap(),
                         qtype: RecordType::A,
-                        qclass: ClassType::Other,
+                        qclass: ClassType::CH,
                     }
                 ],
 
@@ -307,20 +746,20 @@ mod tests {
 
                 want_questions: vec![
                     DNSQuestion {
-                        qname: vec![
+                        qname: Name::from_wire(&[
                             0x07,
                             b'e', b'x', b'a', b'm', b'p', b'l', b'e',
                             0x03,
                             b'c', b'o', b'm',
                             0x00,
-                        ],
+                        ]).unwrap(),
 
-                        qtype: RecordType::Other,
-                        qclass: ClassType::Other,
+                        qtype: RecordType::Unknown(0),
+                        qclass: ClassType::Unknown(0),
                     },
 
                     DNSQuestion {
-                        qname: vec![
+                        qname: Name::from_wire(&[
                             0x04,
                             b't', b'e', b's', b't',
                             0x07,
@@ -328,20 +767,20 @@ mod tests {
                             0x03,
                             b'c', b'o', b'm',
                             0x00,
-                        ],
+                        ]).unwrap(),
 
-                        qtype: RecordType::Other,
-                        qclass: ClassType::Other,
+                        qtype: RecordType::Unknown(0),
+                        qclass: ClassType::Unknown(0),
                     },
 
                     DNSQuestion {
-                        qname: vec![
+                        qname: Name::from_wire(&[
                             0x02,
                             b'i', b'o',
                             0x02,
                             b'i', b'o',
                             0x00,
-                        ],
+                        ]).unwrap(),
 
                         qtype: RecordType::A,
                         qclass: ClassType::IN,
@@ -398,7 +837,7 @@ mod tests {
 
                 want_questions: vec![
                     DNSQuestion {
-                        qname: vec![
+                        qname: Name::from_wire(&[
                             0b00000011, // abc
                             0b01100001,
                             0b01100010,
@@ -426,13 +865,13 @@ mod tests {
                             0b01101111,
                             0b01101101,
                             0b00000000, // null termination
-                        ],
+                        ]).unwrap(),
                         qtype: RecordType::A,
                         qclass: ClassType::IN,
                     },
 
                     DNSQuestion {
-                        qname: vec![
+                        qname: Name::from_wire(&[
                             0b00000011, // def
                             0b01100100,
                             0b01100101,
@@ -460,7 +899,7 @@ mod tests {
                             0b01101111,
                             0b01101101,
                             0b00000000, // null termination
-                        ],
+                        ]).unwrap(),
                         qtype: RecordType::A,
                         qclass: ClassType::IN,
                     }
@@ -470,7 +909,11 @@ mod tests {
 
         for t in tests {
             println!("Running test \"{}\"", t.label);
-            let (got_questions, got_idx) = parse_question(&t.data, t.num_questions);
+            // The test data leaves out the header, so a blank one is put in front of it for the pointers to be relative to
+            let data = [vec![0; HEADER_LEN], t.data].concat();
+            let mut cursor = Cursor::new(&data, HEADER_LEN);
+            let got_questions = parse_question(&mut cursor, t.num_questions).unwrap();
+            let got_idx = cursor.idx - HEADER_LEN;
             for (idx, got_q) in got_questions.iter().enumerate() {
                 assert_eq!(*got_q, t.want_questions[idx]);
                 assert_eq!(got_idx, t.want_idx);
@@ -494,7 +937,7 @@ mod tests {
                 label: "basic test".to_string(),
                 num_records: 1,
                 current_byte: 17,
-                want_idx: 53,
+                want_idx: 54,
                 data: vec![
                     // QUESTION
                     0x07,
@@ -513,7 +956,7 @@ mod tests {
                     0x00,
                     // Type
                     0b0000_0000,
-                    0b0000_0001,
+                    0b0001_0000,
                     // Class
                     0b0000_0000,
                     0b0000_0001,
@@ -524,25 +967,24 @@ mod tests {
                     0b0000_0000,
                     // RDLENGTH
                     0b0000_0000,
-                    0b0000_1101,
+                    0b0000_1110,
                     //RDATA
-                    b'h', b'e', b'l', b'l', b'o', b',', b' ', b'w', b'o', b'r', b'l', b'd', b'!',
+                    0b0000_1101, b'h', b'e', b'l', b'l', b'o', b',', b' ', b'w', b'o', b'r', b'l', b'd', b'!',
                 ],
 
                 want_answers: vec! [
                     ResourceRecord {
-                        name: vec![
+                        name: Name::from_wire(&[
                              0x07,
                             b'e', b'x', b'a', b'm', b'p', b'l', b'e',
                             0x03,
                             b'c', b'o', b'm',
                             0x00,
-                        ],
-                        record_type: RecordType::A,
+                        ]).unwrap(),
+                        record_type: RecordType::TXT,
                         class: ClassType::IN,
                         ttl: 0,
-                        rdlength: 13,
-                        rdata: "hello, world!".to_string().into_bytes(),
+                        rdata: RData::TXT(vec!["hello, world!".to_string().into_bytes()]),
                     }
                 ],
             },
@@ -551,7 +993,7 @@ mod tests {
                 label: "pointer test".to_string(),
                 num_records: 1,
                 current_byte: 17,
-                want_idx: 42,
+                want_idx: 43,
                 data: vec![
                      // QUESTION
                     0x07,
@@ -566,7 +1008,7 @@ mod tests {
                     0b1100_0000, 0b0000_1100, // Ofset relative to fictional header
                     // Type
                     0b0000_0000,
-                    0b0000_0001,
+                    0b0001_0000,
                     // Class
                     0b0000_0000,
                     0b0000_0001,
@@ -577,37 +1019,800 @@ mod tests {
                     0b0000_0000,
                     // RDLENGTH
                     0b0000_0000,
-                    0b0000_1101,
+                    0b0000_1110,
                     //RDATA
-                    b'h', b'e', b'l', b'l', b'o', b',', b' ', b'w', b'o', b'r', b'l', b'd', b'!',
+                    0b0000_1101, b'h', b'e', b'l', b'l', b'o', b',', b' ', b'w', b'o', b'r', b'l', b'd', b'!',
                 ],
 
                 want_answers: vec! [
                     ResourceRecord {
-                        name: vec![
+                        name: Name::from_wire(&[
                             0x07,
                             b'e', b'x', b'a', b'm', b'p', b'l', b'e',
                             0x03,
                             b'c', b'o', b'm',
                             0x00,
-                        ],
-                        record_type: RecordType::A,
+                        ]).unwrap(),
+                        record_type: RecordType::TXT,
                         class: ClassType::IN,
                         ttl: 0,
-                        rdlength: 13,
-                        rdata: "hello, world!".to_string().into_bytes(),
+                        rdata: RData::TXT(vec!["hello, world!".to_string().into_bytes()]),
                     }
                 ],
-            }
+            },
+
+            Test {
+                label: "rdata test".to_string(),
+                num_records: 4,
+                current_byte: 17,
+                want_idx: 132,
+                data: vec![
+                    // QUESTION
+                    0x07,
+                    b'e', b'x', b'a', b'm', b'p', b'l', b'e',
+                    0x03,
+                    b'c', b'o', b'm',
+                    0x00,
+                    0x00, 0x0f,
+                    0x00, 0x01,
+                    // MX ANSWER
+                    0b1100_0000, 0b0000_1100, // Offset relative to fictional header
+                    0x00, 0x0f,
+                    0x00, 0x01,
+                    0x00, 0x00, 0x0e, 0x10,
+                    0x00, 0x09,
+                    0x00, 0x0a, // Preference
+                    0x04, b'm', b'a', b'i', b'l', 0b1100_0000, 0b0000_1100, // Exchange
+                    // AAAA ANSWER
+                    0b1100_0000, 0b0000_1100,
+                    0x00, 0x1c,
+                    0x00, 0x01,
+                    0x00, 0x00, 0x00, 0x00,
+                    0x00, 0x10,
+                    0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+                    // SOA ANSWER
+                    0b1100_0000, 0b0000_1100,
+                    0x00, 0x06,
+                    0x00, 0x01,
+                    0x00, 0x00, 0x00, 0x00,
+                    0x00, 0x27,
+                    0x03, b'n', b's', b'1', 0b1100_0000, 0b0000_1100, // MNAME
+                    0x0a, b'h', b'o', b's', b't', b'm', b'a', b's', b't', b'e', b'r', 0b1100_0000, 0b0000_1100, // RNAME
+                    0x00, 0x00, 0x00, 0x01, // Serial
+                    0x00, 0x00, 0x00, 0x02, // Refresh
+                    0x00, 0x00, 0x00, 0x03, // Retry
+                    0x00, 0x00, 0x00, 0x04, // Expire
+                    0x00, 0x00, 0x00, 0x05, // Minimum
+                    // UNKNOWN ANSWER
+                    0b1100_0000, 0b0000_1100,
+                    0xff, 0x00,
+                    0x00, 0x01,
+                    0x00, 0x00, 0x00, 0x00,
+                    0x00, 0x03,
+                    0x01, 0x02, 0x03,
+                ],
+
+                want_answers: vec![
+                    ResourceRecord {
+                        name: Name::from_wire(&[0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00]).unwrap(),
+                        record_type: RecordType::MX,
+                        class: ClassType::IN,
+                        ttl: 3600,
+                        rdata: RData::MX {
+                            preference: 10,
+                            exchange: Name::from_wire(&[0x04, b'm', b'a', b'i', b'l', 0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00]).unwrap(),
+                        },
+                    },
+
+                    ResourceRecord {
+                        name: Name::from_wire(&[0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00]).unwrap(),
+                        record_type: RecordType::AAAA,
+                        class: ClassType::IN,
+                        ttl: 0,
+                        rdata: RData::AAAA(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+                    },
+
+                    ResourceRecord {
+                        name: Name::from_wire(&[0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00]).unwrap(),
+                        record_type: RecordType::SOA,
+                        class: ClassType::IN,
+                        ttl: 0,
+                        rdata: RData::SOA {
+                            mname: Name::from_wire(&[0x03, b'n', b's', b'1', 0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00]).unwrap(),
+                            rname: Name::from_wire(&[
+                                0x0a, b'h', b'o', b's', b't', b'm', b'a', b's', b't', b'e', b'r',
+                                0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00,
+                            ]).unwrap(),
+                            serial: 1, refresh: 2, retry: 3, expire: 4, minimum: 5,
+                        },
+                    },
+
+                    ResourceRecord {
+                        name: Name::from_wire(&[0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00]).unwrap(),
+                        record_type: RecordType::Unknown(0xff00),
+                        class: ClassType::IN,
+                        ttl: 0,
+                        rdata: RData::Unknown { rtype: RecordType::Unknown(0xff00), bytes: vec![0x01, 0x02, 0x03] },
+                    },
+                ],
+            },
         ];
 
         for t in tests {
             println!("Running test \"{}\"", t.label);
-            let (got_records, got_idx) = parse_record(&t.data, t.num_records, t.current_byte);
+            // The test data leaves out the header, so a blank one is put in front of it for the pointers to be relative to
+            let data = [vec![0; HEADER_LEN], t.data].concat();
+            let mut cursor = Cursor::new(&data, HEADER_LEN + t.current_byte);
+            let got_records = parse_record(&mut cursor, t.num_records).unwrap();
+            let got_idx = cursor.idx - HEADER_LEN;
             for (idx, got_a) in got_records.iter().enumerate() {
                 assert_eq!(*got_a, t.want_answers[idx]);
                 assert_eq!(got_idx, t.want_idx);
             }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_parse_message() {
+        struct Test {
+            label: String,
+            data: Vec<u8>,
+            want: DNSMessage,
+        }
+
+        let tests: Vec<Test> = vec![
+            Test {
+                label: "all sections".to_string(),
+                data: vec![
+                    // HEADER
+                    0b0000_0100, 0b1101_0010, // ID: 1234
+                    0b1000_0001, // QR: 1, Opcode: 0, AA: 0, TC: 0, RD: 1
+                    0b1000_0000, // RA: 1, Z: 0, RCODE: 0
+                    0x00, 0x01, // QDCOUNT: 1
+                    0x00, 0x01, // ANCOUNT: 1
+                    0x00, 0x01, // NSCOUNT: 1
+                    0x00, 0x01, // ARCOUNT: 1
+                    // QUESTION
+                    0x02, b'i', b'o', 0x00,
+                    0x00, 0x01,
+                    0x00, 0x01,
+                    // ANSWER
+                    0b1100_0000, 0b0000_1100,
+                    0x00, 0x01,
+                    0x00, 0x01,
+                    0x00, 0x00, 0x00, 0x3c,
+                    0x00, 0x04,
+                    1, 2, 3, 4,
+                    // AUTHORITY
+                    0b1100_0000, 0b0000_1100,
+                    0x00, 0x02,
+                    0x00, 0x01,
+                    0x00, 0x00, 0x00, 0x3c,
+                    0x00, 0x06,
+                    0x03, b'n', b's', b'1', 0b1100_0000, 0b0000_1100,
+                    // ADDITIONAL
+                    0b1100_0000, 0b0011_0000, // Points at the NS record's RDATA
+                    0x00, 0x01,
+                    0x00, 0x01,
+                    0x00, 0x00, 0x00, 0x3c,
+                    0x00, 0x04,
+                    5, 6, 7, 8,
+                ],
+
+                want: DNSMessage {
+                    header: DNSHeader {
+                        id: 1234,
+                        qr: QR::Response,
+                        opcode: Opcode::QUERY,
+                        aa: false, tc: false, rd: true, ra: true, z: 0, ad: false, cd: false,
+                        rcode: RCODE::NoError,
+                        qdcount: 1, ancount: 1, nscount: 1, arcount: 1,
+                    },
+                    questions: vec![
+                        DNSQuestion {
+                            qname: Name::from_wire(&[0x02, b'i', b'o', 0x00]).unwrap(),
+                            qtype: RecordType::A,
+                            qclass: ClassType::IN,
+                        },
+                    ],
+                    answers: vec![
+                        ResourceRecord {
+                            name: Name::from_wire(&[0x02, b'i', b'o', 0x00]).unwrap(),
+                            record_type: RecordType::A,
+                            class: ClassType::IN,
+                            ttl: 60,
+                            rdata: RData::A(Ipv4Addr::new(1, 2, 3, 4)),
+                        },
+                    ],
+                    authorities: vec![
+                        ResourceRecord {
+                            name: Name::from_wire(&[0x02, b'i', b'o', 0x00]).unwrap(),
+                            record_type: RecordType::NS,
+                            class: ClassType::IN,
+                            ttl: 60,
+                            rdata: RData::NS(Name::from_wire(&[0x03, b'n', b's', b'1', 0x02, b'i', b'o', 0x00]).unwrap()),
+                        },
+                    ],
+                    additionals: vec![
+                        ResourceRecord {
+                            name: Name::from_wire(&[0x03, b'n', b's', b'1', 0x02, b'i', b'o', 0x00]).unwrap(),
+                            record_type: RecordType::A,
+                            class: ClassType::IN,
+                            ttl: 60,
+                            rdata: RData::A(Ipv4Addr::new(5, 6, 7, 8)),
+                        },
+                    ],
+                    edns: None,
+                },
+            },
+
+            Test {
+                label: "EDNS".to_string(),
+                data: vec![
+                    // HEADER
+                    0b0000_0100, 0b1101_0010, // ID: 1234
+                    0b1000_0000, // QR: 1, Opcode: 0, AA: 0, TC: 0, RD: 0
+                    0b0000_0111, // RA: 0, Z: 0, AD: 0, CD: 0, RCODE: 7
+                    0x00, 0x00, // QDCOUNT: 0
+                    0x00, 0x00, // ANCOUNT: 0
+                    0x00, 0x00, // NSCOUNT: 0
+                    0x00, 0x02, // ARCOUNT: 2
+                    // ADDITIONAL
+                    0x00,
+                    0x00, 0x29, // OPT
+                    0x04, 0xd0, // UDP payload size: 1232
+                    0x01, 0x00, 0x80, 0x01, // Extended RCODE: 1, version: 0, DO: 1, Z: 1
+                    0x00, 0x15,
+                    0xfd, 0xe9, 0x00, 0x02, 0xab, 0xcd, // Option 65001
+                    0x00, 0x0f, 0x00, 0x0b, // EDE
+                    0x00, 0x17, b'i', b'o', b' ', b'i', b's', b' ', b'd', b'o', b'w', // Network Error
+                    0x02, b'i', b'o', 0x00,
+                    0x00, 0x01, // A
+                    0x00, 0x01, // IN
+                    0x00, 0x00, 0x00, 0x3c,
+                    0x00, 0x04,
+                    1, 2, 3, 4,
+                ],
+
+                want: DNSMessage {
+                    header: DNSHeader {
+                        id: 1234,
+                        qr: QR::Response,
+                        opcode: Opcode::QUERY,
+                        aa: false, tc: false, rd: false, ra: false, z: 0, ad: false, cd: false,
+                        rcode: RCODE::BadCookie,
+                        qdcount: 0, ancount: 0, nscount: 0, arcount: 1,
+                    },
+                    questions: Vec::new(),
+                    answers: Vec::new(),
+                    authorities: Vec::new(),
+                    additionals: vec![
+                        ResourceRecord {
+                            name: Name::from_wire(&[0x02, b'i', b'o', 0x00]).unwrap(),
+                            record_type: RecordType::A,
+                            class: ClassType::IN,
+                            ttl: 60,
+                            rdata: RData::A(Ipv4Addr::new(1, 2, 3, 4)),
+                        },
+                    ],
+                    edns: Some(Edns {
+                        udp_payload_size: 1232,
+                        version: 0,
+                        dnssec_ok: true,
+                        z: 1,
+                        options: vec![
+                            EdnsOption::Unknown { code: 65001, data: vec![0xab, 0xcd] },
+                            EdnsOption::ExtendedError { info_code: ExtendedErrorCode::NetworkError, extra_text: "io is dow".to_string() },
+                        ],
+                    }),
+                },
+            },
+
+            Test {
+                label: "EDNS client subnet".to_string(),
+                data: vec![
+                    // HEADER
+                    0b0000_0100, 0b1101_0010, // ID: 1234
+                    0b0000_0001, // QR: 0, Opcode: 0, AA: 0, TC: 0, RD: 1
+                    0b0000_0000, // RA: 0, Z: 0, AD: 0, CD: 0, RCODE: 0
+                    0x00, 0x00, // QDCOUNT: 0
+                    0x00, 0x00, // ANCOUNT: 0
+                    0x00, 0x00, // NSCOUNT: 0
+                    0x00, 0x01, // ARCOUNT: 1
+                    // ADDITIONAL
+                    0x00,
+                    0x00, 0x29, // OPT
+                    0x04, 0xd0, // UDP payload size: 1232
+                    0x00, 0x00, 0x00, 0x00,
+                    0x00, 0x1a,
+                    0x00, 0x08, 0x00, 0x07, // ECS
+                    0x00, 0x01, 20, 0, // IPv4, source prefix: 20, scope prefix: 0
+                    192, 0, 16,
+                    0x00, 0x08, 0x00, 0x0b, // ECS
+                    0x00, 0x02, 56, 48, // IPv6, source prefix: 56, scope prefix: 48
+                    0x20, 0x01, 0x0d, 0xb8, 0x12, 0x34, 0x56,
+                ],
+
+                want: DNSMessage {
+                    header: DNSHeader {
+                        id: 1234,
+                        qr: QR::Query,
+                        opcode: Opcode::QUERY,
+                        aa: false, tc: false, rd: true, ra: false, z: 0, ad: false, cd: false,
+                        rcode: RCODE::NoError,
+                        qdcount: 0, ancount: 0, nscount: 0, arcount: 0,
+                    },
+                    questions: Vec::new(),
+                    answers: Vec::new(),
+                    authorities: Vec::new(),
+                    additionals: Vec::new(),
+                    edns: Some(Edns {
+                        udp_payload_size: 1232,
+                        version: 0,
+                        dnssec_ok: false,
+                        z: 0,
+                        options: vec![
+                            EdnsOption::ClientSubnet {
+                                source_prefix: 20,
+                                scope_prefix: 0,
+                                address: IpAddr::V4(Ipv4Addr::new(192, 0, 16, 0)),
+                            },
+                            EdnsOption::ClientSubnet {
+                                source_prefix: 56,
+                                scope_prefix: 48,
+                                address: IpAddr::V6(Ipv6Addr::new(0x2001, 0x0db8, 0x1234, 0x5600, 0, 0, 0, 0)),
+                            },
+                        ],
+                    }),
+                },
+            },
+        ];
+
+        for t in tests {
+            println!("Running test \"{}\"", t.label);
+            let got = parse_message(&t.data).unwrap();
+            assert_eq!(got, t.want);
+        }
+    }
+
+    #[test]
+    fn test_parse_message_errors() {
+        struct Test {
+            label: String,
+            data: Vec<u8>,
+            want: ParseError,
+        }
+
+        // Header for a query with the given question and answer counts
+        let header = |qdcount: u8, ancount: u8| -> Vec<u8> {
+            vec![
+                0x04, 0xd2,
+                0x01, 0x00,
+                0x00, qdcount,
+                0x00, ancount,
+                0x00, 0x00,
+                0x00, 0x00,
+            ]
+        };
+
+        // Header for a query with the given additional count
+        let additional_header = |arcount: u8| -> Vec<u8> {
+            vec![
+                0x04, 0xd2,
+                0x01, 0x00,
+                0x00, 0x00,
+                0x00, 0x00,
+                0x00, 0x00,
+                0x00, arcount,
+            ]
+        };
+
+        let opt = vec![0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+        // Two records, where the first one's RDATA is a root name followed by pointers that each point at the one before them, and the
+        // second one's name points at the last of those pointers
+        let mut pointer_chain: Vec<u8> = vec![
+            0x00,
+            0xff, 0x00,
+            0x00, 0x01,
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 1 + 2 * MAX_POINTERS as u8,
+            0x00,
+        ];
+
+        for i in 0..MAX_POINTERS {
+            let target = if i == 0 { 23 } else { 24 + 2 * (i - 1) };
+            pointer_chain.extend_from_slice(&[0b1100_0000, target as u8]);
+        }
+
+        pointer_chain.extend_from_slice(&[
+            0b1100_0000, (24 + 2 * (MAX_POINTERS - 1)) as u8,
+            0x00, 0x01,
+            0x00, 0x01,
+            0x00, 0x00, 0x00, 0x00,
+            0x00, 0x04,
+            1, 2, 3, 4,
+        ]);
+
+        let tests: Vec<Test> = vec![
+            Test {
+                label: "empty datagram".to_string(),
+                data: vec![],
+                want: ParseError::TruncatedHeader { offset: 0 },
+            },
+
+            Test {
+                label: "short header".to_string(),
+                data: vec![0x04, 0xd2, 0x01],
+                want: ParseError::TruncatedHeader { offset: 3 },
+            },
+
+            Test {
+                label: "missing question".to_string(),
+                data: header(1, 0),
+                want: ParseError::TruncatedName { offset: 12 },
+            },
+
+            Test {
+                label: "label runs past the end".to_string(),
+                data: [header(1, 0), vec![0x07, b'e', b'x', b'a']].concat(),
+                want: ParseError::TruncatedName { offset: 16 },
+            },
+
+            Test {
+                label: "label too long".to_string(),
+                data: [header(1, 0), vec![0b0100_0000, b'e', b'x', b'a']].concat(),
+                want: ParseError::LabelTooLong { offset: 12, length: 64 },
+            },
+
+            Test {
+                label: "pointer into the header".to_string(),
+                data: [header(1, 0), vec![0b1100_0000, 0b0000_0010, 0x00, 0x01, 0x00, 0x01]].concat(),
+                want: ParseError::BadPointer { offset: 12 },
+            },
+
+            Test {
+                label: "pointer past the end".to_string(),
+                data: [header(1, 0), vec![0b1100_0000, 0b1111_0000, 0x00, 0x01, 0x00, 0x01]].concat(),
+                want: ParseError::BadPointer { offset: 12 },
+            },
+
+            Test {
+                label: "self-referencing pointer".to_string(),
+                data: [header(1, 0), vec![0b1100_0000, 0b0000_1100, 0x00, 0x01, 0x00, 0x01]].concat(),
+                want: ParseError::BadPointer { offset: 12 },
+            },
+
+            Test {
+                label: "forward pointer".to_string(),
+                data: [header(1, 0), vec![0b1100_0000, 0b0000_1110, 0x00, 0x00, 0x01, 0x00, 0x01]].concat(),
+                want: ParseError::BadPointer { offset: 12 },
+            },
+
+            Test {
+                label: "pointer loop".to_string(),
+                data: [header(1, 0), vec![0x01, b'a', 0b1100_0000, 0b0000_1100, 0x00, 0x01, 0x00, 0x01]].concat(),
+                want: ParseError::BadPointer { offset: 14 },
+            },
+
+            Test {
+                label: "pointer chain too long".to_string(),
+                data: [header(0, 2), pointer_chain].concat(),
+                want: ParseError::TooManyPointers { offset: 24 },
+            },
+
+            Test {
+                label: "name too long".to_string(),
+                data: [header(1, 0), [vec![63], vec![b'a'; 63]].concat().repeat(4), vec![0x00, 0x00, 0x01, 0x00, 0x01]].concat(),
+                want: ParseError::NameTooLong { offset: 12 },
+            },
+
+            Test {
+                label: "question missing class".to_string(),
+                data: [header(1, 0), vec![0x02, b'i', b'o', 0x00, 0x00, 0x01]].concat(),
+                want: ParseError::Truncated { offset: 18 },
+            },
+
+            Test {
+                label: "rdlength overrun".to_string(),
+                data: [header(0, 1), vec![
+                    0x00,
+                    0x00, 0x01,
+                    0x00, 0x01,
+                    0x00, 0x00, 0x00, 0x00,
+                    0x00, 0x04,
+                    192, 168,
+                ]].concat(),
+                want: ParseError::RdlengthOverrun { offset: 23, rdlength: 4, remaining: 2 },
+            },
+
+            Test {
+                label: "A record with the wrong length".to_string(),
+                data: [header(0, 1), vec![
+                    0x00,
+                    0x00, 0x01,
+                    0x00, 0x01,
+                    0x00, 0x00, 0x00, 0x00,
+                    0x00, 0x03,
+                    192, 168, 0,
+                ]].concat(),
+                want: ParseError::BadRdata { offset: 23, record_type: RecordType::A },
+            },
+
+            Test {
+                label: "name overruns rdlength".to_string(),
+                data: [header(0, 1), vec![
+                    0x00,
+                    0x00, 0x02,
+                    0x00, 0x01,
+                    0x00, 0x00, 0x00, 0x00,
+                    0x00, 0x02,
+                    0x02, b'i', b'o', 0x00,
+                ]].concat(),
+                want: ParseError::BadRdata { offset: 23, record_type: RecordType::NS },
+            },
+
+            Test {
+                label: "two OPT records".to_string(),
+                data: [additional_header(2), opt.clone(), opt.clone()].concat(),
+                want: ParseError::BadOpt { offset: 23 },
+            },
+
+            Test {
+                label: "OPT record not owned by the root".to_string(),
+                data: [additional_header(1), vec![0x02, b'i', b'o'], opt.clone()].concat(),
+                want: ParseError::BadOpt { offset: 12 },
+            },
+
+            Test {
+                label: "EDNS option overruns rdlength".to_string(),
+                data: [additional_header(1), vec![
+                    0x00,
+                    0x00, 0x29,
+                    0x04, 0xd0,
+                    0x00, 0x00, 0x00, 0x00,
+                    0x00, 0x05,
+                    0xfd, 0xe9, 0x00, 0x02, 0xab,
+                ]].concat(),
+                want: ParseError::BadRdata { offset: 23, record_type: RecordType::OPT },
+            },
+
+            Test {
+                label: "EDNS client subnet with bits past the prefix".to_string(),
+                data: [additional_header(1), vec![
+                    0x00,
+                    0x00, 0x29,
+                    0x04, 0xd0,
+                    0x00, 0x00, 0x00, 0x00,
+                    0x00, 0x0b,
+                    0x00, 0x08, 0x00, 0x07,
+                    0x00, 0x01, 20, 0,
+                    192, 0, 31,
+                ]].concat(),
+                want: ParseError::BadRdata { offset: 23, record_type: RecordType::OPT },
+            },
+
+            Test {
+                label: "EDNS client subnet with an unknown family".to_string(),
+                data: [additional_header(1), vec![
+                    0x00,
+                    0x00, 0x29,
+                    0x04, 0xd0,
+                    0x00, 0x00, 0x00, 0x00,
+                    0x00, 0x08,
+                    0x00, 0x08, 0x00, 0x04,
+                    0x00, 0x03, 0, 0,
+                ]].concat(),
+                want: ParseError::BadRdata { offset: 23, record_type: RecordType::OPT },
+            },
+
+            Test {
+                label: "server cookie too short".to_string(),
+                data: [additional_header(1), vec![
+                    0x00,
+                    0x00, 0x29,
+                    0x04, 0xd0,
+                    0x00, 0x00, 0x00, 0x00,
+                    0x00, 0x10,
+                    0x00, 0x0a, 0x00, 0x0c,
+                    1, 2, 3, 4, 5, 6, 7, 8,
+                    1, 2, 3, 4,
+                ]].concat(),
+                want: ParseError::BadRdata { offset: 23, record_type: RecordType::OPT },
+            },
+
+            Test {
+                label: "trailing garbage".to_string(),
+                data: [header(1, 0), vec![0x02, b'i', b'o', 0x00, 0x00, 0x01, 0x00, 0x01, 0xff]].concat(),
+                want: ParseError::TrailingGarbage { offset: 20 },
+            },
+        ];
+
+        for t in tests {
+            println!("Running test \"{}\"", t.label);
+            let got = parse_message(&t.data);
+            assert_eq!(got.err(), Some(t.want));
+        }
+    }
+
+    #[test]
+    fn test_parse_query() {
+        struct Test {
+            label: String,
+            data: Vec<u8>,
+            want: Result<DNSMessage, ParseError>,
+        }
+
+        // Header for a query with the given question, answer and additional counts
+        let header_data = |qdcount: u8, ancount: u8, arcount: u8| -> Vec<u8> {
+            vec![
+                0b0000_0100, 0b1101_0010, // ID: 1234
+                0b0000_0001, // QR: 0, Opcode: 0, AA: 0, TC: 0, RD: 1
+                0b0000_0000, // RA: 0, Z: 0, AD: 0, CD: 0, RCODE: 0
+                0x00, qdcount,
+                0x00, ancount,
+                0x00, 0x00,
+                0x00, arcount,
+            ]
+        };
+
+        let question_data: Vec<u8> = vec![
+            0x02, b'i', b'o', 0x00,
+            0x00, 0x01, // A
+            0x00, 0x01, // IN
+        ];
+
+        let opt_data: Vec<u8> = vec![
+            0x00,
+            0x00, 0x29, // OPT
+            0x04, 0xd0, // UDP payload size: 1232
+            0x00, 0x00, 0x80, 0x00, // Extended RCODE: 0, version: 0, DO: 1, Z: 0
+            0x00, 0x00,
+        ];
+
+        let tests: Vec<Test> = vec![
+            Test {
+                label: "query with EDNS".to_string(),
+                data: [header_data(1, 1, 1), question_data.clone(), vec![
+                    // ANSWER, whose RDATA is too short for an A record but is never decoded
+                    0x02, b'i', b'o', 0x00,
+                    0x00, 0x01, // A
+                    0x00, 0x01, // IN
+                    0x00, 0x00, 0x00, 0x3c,
+                    0x00, 0x03,
+                    1, 2, 3,
+                ], opt_data.clone()].concat(),
+
+                want: Ok(DNSMessage {
+                    header: DNSHeader {
+                        id: 1234,
+                        qr: QR::Query,
+                        opcode: Opcode::QUERY,
+                        aa: false, tc: false, rd: true, ra: false, z: 0, ad: false, cd: false,
+                        rcode: RCODE::NoError,
+                        qdcount: 1, ancount: 0, nscount: 0, arcount: 0,
+                    },
+                    questions: vec![
+                        DNSQuestion {
+                            qname: Name::from_wire(&[0x02, b'i', b'o', 0x00]).unwrap(),
+                            qtype: RecordType::A,
+                            qclass: ClassType::IN,
+                        },
+                    ],
+                    answers: Vec::new(),
+                    authorities: Vec::new(),
+                    additionals: Vec::new(),
+                    edns: Some(Edns { udp_payload_size: 1232, version: 0, dnssec_ok: true, z: 0, options: Vec::new() }),
+                }),
+            },
+
+            Test {
+                label: "two OPT records".to_string(),
+                data: [header_data(1, 0, 2), question_data.clone(), opt_data.clone(), opt_data.clone()].concat(),
+                want: Err(ParseError::BadOpt { offset: 31 }),
+            },
+
+            Test {
+                label: "record runs past the end".to_string(),
+                data: [header_data(0, 1, 0), vec![
+                    0x00,
+                    0x00, 0x01,
+                    0x00, 0x01,
+                    0x00, 0x00, 0x00, 0x3c,
+                    0x00, 0x04,
+                    1, 2,
+                ]].concat(),
+                want: Err(ParseError::RdlengthOverrun { offset: 23, rdlength: 4, remaining: 2 }),
+            },
+
+            Test {
+                label: "trailing garbage".to_string(),
+                data: [header_data(1, 0, 0), question_data.clone(), vec![0xff]].concat(),
+                want: Err(ParseError::TrailingGarbage { offset: 20 }),
+            },
+        ];
+
+        for t in tests {
+            println!("Running test \"{}\"", t.label);
+            assert_eq!(parse_query(&t.data), t.want);
+        }
+    }
+
+    #[test]
+    fn test_parse_partial() {
+        struct Test {
+            label: String,
+            data: Vec<u8>,
+            want: Option<PartialMessage>,
+        }
+
+        let header_data: Vec<u8> = vec![
+            0b0000_0100, 0b1101_0010, // ID: 1234
+            0b0000_0001, // QR: 0, Opcode: 0, AA: 0, TC: 0, RD: 1
+            0b0000_0000, // RA: 0, Z: 0, AD: 0, CD: 0, RCODE: 0
+            0x00, 0x01, // QDCOUNT: 1
+            0x00, 0x01, // ANCOUNT: 1
+            0x00, 0x00, // NSCOUNT: 0
+            0x00, 0x00, // ARCOUNT: 0
+        ];
+
+        let header = DNSHeader {
+            id: 1234,
+            qr: QR::Query,
+            opcode: Opcode::QUERY,
+            aa: false, tc: false, rd: true, ra: false, z: 0, ad: false, cd: false,
+            rcode: RCODE::NoError,
+            qdcount: 1, ancount: 1, nscount: 0, arcount: 0,
+        };
+
+        let question_data: Vec<u8> = vec![
+            0x02,
+            b'i', b'o',
+            0x00,
+            0x00, 0x01,
+            0x00, 0x01,
+        ];
+
+        let tests: Vec<Test> = vec![
+            Test {
+                label: "unreadable ID".to_string(),
+                data: vec![0b0000_0100],
+                want: None,
+            },
+
+            Test {
+                label: "ID only".to_string(),
+                data: header_data[0..5].to_vec(),
+                want: Some(PartialMessage { id: 1234, header: None, questions: Vec::new() }),
+            },
+
+            Test {
+                label: "truncated question".to_string(),
+                data: [header_data.clone(), question_data[0..6].to_vec()].concat(),
+                want: Some(PartialMessage { id: 1234, header: Some(header), questions: Vec::new() }),
+            },
+
+            Test {
+                label: "truncated answer".to_string(),
+                data: [header_data.clone(), question_data.clone(), vec![0b1100_0000]].concat(),
+                want: Some(PartialMessage {
+                    id: 1234,
+                    header: Some(header),
+                    questions: vec![
+                        DNSQuestion {
+                            qname: Name::from_wire(&[0x02, b'i', b'o', 0x00]).unwrap(),
+                            qtype: RecordType::A,
+                            qclass: ClassType::IN,
+                        }
+                    ],
+                }),
+            },
+        ];
+
+        for t in tests {
+            println!("Running test \"{}\"", t.label);
+            let got = parse_partial(&t.data);
+            assert_eq!(got, t.want);
+        }
+    }
+}
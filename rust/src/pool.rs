@@ -0,0 +1,86 @@
+use std::{
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed number of threads that run jobs from a queue of limited length. Dropping the pool waits for the queued jobs to finish.
+pub struct WorkerPool {
+    sender: Option<mpsc::SyncSender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn new(workers: usize, queue_len: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_len);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..workers)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    // The lock is only held while waiting for a job, not while running it
+                    let job = receiver.lock().unwrap().recv();
+
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => return, // The pool was dropped and the queue is empty
+                    }
+                })
+            })
+            .collect();
+
+        WorkerPool { sender: Some(sender), workers }
+    }
+
+    /// Queues a job, returning false without queueing it if the queue is full
+    pub fn try_execute(&self, job: impl FnOnce() + Send + 'static) -> bool {
+        let sender = self.sender.as_ref().expect("sender is only taken when the pool is dropped");
+        sender.try_send(Box::new(job)).is_ok()
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        // Closing the queue lets each worker stop once there's nothing left in it
+        self.sender.take();
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_worker_pool() {
+        let pool = WorkerPool::new(1, 1);
+        let done = Arc::new(AtomicUsize::new(0));
+
+        // Keep the only worker busy until the test lets it go
+        let (release, wait) = mpsc::channel::<()>();
+        let (started, has_started) = mpsc::channel::<()>();
+        assert!(pool.try_execute(move || {
+            started.send(()).unwrap();
+            wait.recv().unwrap();
+        }));
+        has_started.recv().unwrap();
+
+        let queued = Arc::clone(&done);
+        assert!(pool.try_execute(move || {
+            queued.fetch_add(1, Ordering::SeqCst);
+        }));
+
+        // The queue only has room for one job
+        assert!(!pool.try_execute(|| ()));
+
+        release.send(()).unwrap();
+        drop(pool);
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+    }
+}
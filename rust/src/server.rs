@@ -0,0 +1,1216 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+
+use crate::{
+    build::{build_message, encode_message, BuildError, MessageBuilder},
+    config::Config,
+    cookie::{self, CookiePolicy, RateLimiter, ServerCookies, UpstreamCookie},
+    parse::{parse_message, parse_partial, parse_query, ParseError},
+    tcp,
+    types::{self, DNSHeader, DNSMessage, Edns, EdnsOption, ExtendedErrorCode, Opcode, RData, ResourceRecord, QR, RCODE},
+    view::MessageView,
+};
+
+/// The UDP payload size we advertise, and the most we'll accept. It's small enough to avoid IP fragmentation on practically any path.
+pub const UDP_PAYLOAD_SIZE: u16 = 1232;
+
+// Clients that don't use EDNS can only receive 512 bytes over UDP (RFC 1035 section 4.2.1)
+const MIN_UDP_PAYLOAD_SIZE: u16 = 512;
+
+// The only EDNS version that exists so far
+const EDNS_VERSION: u8 = 0;
+
+/// The longest a message can be. TCP messages have a 16-bit length prefix (RFC 1035 section 4.2.2), and a UDP datagram can't be
+/// much longer, so datagrams should be received into a buffer this big. Anything shorter can cut off a query or an upstream answer
+/// that's bigger than we'd send ourselves.
+pub const MAX_MESSAGE_LEN: usize = u16::MAX as usize;
+
+// How long to wait for the forward server before telling the client it couldn't be reached
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How a query reached us
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum Transport {
+    Udp,
+    Tcp, // The client's address can't be spoofed, since it had to complete the handshake
+}
+
+// Cookie state that outlives a single query, shared by all the queries being answered at once
+struct Cookies {
+    server: Mutex<ServerCookies>,
+    upstream: Mutex<UpstreamCookie>,
+    unverified: Mutex<RateLimiter>, // Answers to clients without a valid server cookie, for CookiePolicy::RateLimit
+}
+
+/// Ways that setting up a server can fail
+#[derive(Debug, Error)]
+pub enum ServerError {
+    #[error("failed to look up the forward server's address: {0}")]
+    Upstream(#[from] io::Error),
+
+    #[error("failed to get random bytes for DNS cookies: {0}")]
+    Random(#[from] getrandom::Error),
+}
+
+/// Answers queries, either by resolving them itself or by forwarding them to the resolver in its config. It doesn't do any I/O with
+/// clients, so it can sit behind any transport, and any number of queries can be answered at once from different threads.
+pub struct Server {
+    config: Config,
+    forward_addr: Option<SocketAddr>,
+    upstream_timeout: Duration,
+    cookies: Cookies,
+}
+
+impl Server {
+    /// Sets up a server, looking up the forward server's address if there is one
+    pub fn new(config: Config) -> Result<Self, ServerError> {
+        let forward_addr = match &config.resolver {
+            Some(resolver) => match resolver.to_socket_addrs()?.next() {
+                Some(addr) => Some(addr),
+                None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has no addresses", resolver)).into()),
+            },
+            None => None,
+        };
+
+        let cookies = Cookies {
+            server: Mutex::new(ServerCookies::new(cookie::now())?),
+            upstream: Mutex::new(UpstreamCookie::new()?),
+            unverified: Mutex::new(RateLimiter::new(match config.cookie_policy {
+                CookiePolicy::RateLimit(limit) => limit,
+                _ => 0,
+            })),
+        };
+
+        Ok(Server { config, forward_addr, upstream_timeout: UPSTREAM_TIMEOUT, cookies })
+    }
+
+    /// Answers a datagram from a UDP client, returning the response to send back, or None if it should be ignored
+    pub fn handle_udp(&self, data: &[u8], source: SocketAddr) -> Option<Vec<u8>> {
+        self.handle_message(data, source, Transport::Udp)
+    }
+
+    /// Answers a message from a TCP client, without the length prefix. The response doesn't have one either.
+    pub fn handle_tcp(&self, data: &[u8], source: SocketAddr) -> Option<Vec<u8>> {
+        self.handle_message(data, source, Transport::Tcp)
+    }
+
+    fn handle_message(&self, data: &[u8], source: SocketAddr, transport: Transport) -> Option<Vec<u8>> {
+        // Replying to responses could start a loop between two servers, and the header is enough to spot them
+        if MessageView::new(data).is_ok_and(|view| view.header().qr == QR::Response) {
+            return None;
+        }
+
+        match parse_query(data) {
+            Ok(msg) => {
+                let max_len = max_response_len(msg.edns.as_ref(), transport);
+                let forward = self.forward_addr.map(|addr| (addr, self.upstream_timeout));
+                handle_query(msg, source, transport, forward, &self.config, &self.cookies)
+                    .and_then(|r| build_response(r, max_len))
+            },
+
+            Err(e) => {
+                eprintln!("received malformed message from {}: {}", source, e);
+                format_error_response(data).and_then(|r| build_response(r, max_response_len(None, transport)))
+            }
+        }
+    }
+}
+
+// Works out the response to a decoded message, or None if there shouldn't be one
+fn handle_query(
+    msg: DNSMessage,
+    source: SocketAddr,
+    transport: Transport,
+    forward: Option<(SocketAddr, Duration)>, // The forward server's address, and how long to wait for it
+    config: &Config,
+    cookies: &Cookies,
+) -> Option<DNSMessage> {
+    let now = cookie::now();
+
+    // A valid server cookie shows that the client really is at its source address, since it got the cookie from us there before
+    let client_cookie = cookie::find_cookie(msg.edns.as_ref()).map(|(client, server)| {
+        (client, cookies.server.lock().unwrap().validate(client, server, source.ip(), now))
+    });
+
+    let admitted = match (client_cookie, config.cookie_policy) {
+        _ if transport == Transport::Tcp => true,
+        (Some((_, true)), _) | (_, CookiePolicy::Allow) => true,
+        (_, CookiePolicy::RateLimit(_)) => cookies.unverified.lock().unwrap().allow(now),
+        (_, CookiePolicy::Tcp) => false,
+    };
+
+    let response = match forward {
+        // Without knowing what a newer version of EDNS means, we can't answer anything but BADVERS (RFC 6891 section 6.1.3)
+        _ if msg.edns.as_ref().is_some_and(|e| e.version > EDNS_VERSION) => Some(error_response(msg, RCODE::BadVers)),
+
+        // Clients that sent a cookie can take the server cookie from a BADCOOKIE response and try again (RFC 7873 section 5.3),
+        // the others have to switch to TCP
+        _ if !admitted && client_cookie.is_some() => Some(error_response(msg, RCODE::BadCookie)),
+        _ if !admitted => Some(truncated_response(msg)),
+
+        // This server only handles standard queries, so we need to indicate that other request types aren't handled
+        _ if msg.header.opcode != Opcode::QUERY => {
+            let text = format!("opcode {} is not supported", u8::from(msg.header.opcode));
+            Some(extended_error_response(msg, RCODE::NotImplemented, ExtendedErrorCode::NotSupported, text))
+        },
+
+        Some((forward_addr, timeout)) => {
+            match forward_request(&msg, source, forward_addr, timeout, config, &cookies.upstream) {
+                Ok(response) => Some(response),
+                Err(e) => {
+                    eprintln!("failed to forward request: {}", e);
+                    Some(extended_error_response(msg, RCODE::ServerFailure, e.extended_error(), e.to_string()))
+                },
+            }
+        },
+
+        None => Some(resolve_request(msg)),
+    };
+
+    // Every response to a client that sent a cookie gets a fresh server cookie (RFC 7873 section 5.2)
+    match (response, client_cookie) {
+        (Some(mut response), Some((client, _))) => {
+            if let Some(edns) = response.edns.as_mut() {
+                edns.options.push(EdnsOption::Cookie { client, server: cookies.server.lock().unwrap().generate(client, source.ip(), now) });
+            }
+
+            Some(response)
+        },
+
+        (response, _) => response,
+    }
+}
+
+// The most a client can receive, which over UDP goes by the payload size in its OPT record
+fn max_response_len(edns: Option<&Edns>, transport: Transport) -> usize {
+    let size = match (edns, transport) {
+        (_, Transport::Tcp) => return MAX_MESSAGE_LEN,
+        (Some(e), Transport::Udp) => e.udp_payload_size.clamp(MIN_UDP_PAYLOAD_SIZE, UDP_PAYLOAD_SIZE),
+        (None, Transport::Udp) => MIN_UDP_PAYLOAD_SIZE,
+    };
+
+    size as usize
+}
+
+// The OPT record to send back, which a response should only have if the query did (RFC 6891 section 7)
+fn response_edns(query_edns: Option<&Edns>) -> Option<Edns> {
+    query_edns.map(|e| Edns {
+        udp_payload_size: UDP_PAYLOAD_SIZE,
+        version: EDNS_VERSION,
+        dnssec_ok: e.dnssec_ok, // The DO bit is copied from the query (RFC 3225 section 3)
+        z: 0,
+        options: Vec::new(),
+    })
+}
+
+// Encodes a response, or a SERVFAIL if it can't be encoded. Only records put together by hand can fail to encode, e.g. with a
+// character-string that's too long, never ones that were decoded from the forward server's answer.
+fn build_response(mut msg: DNSMessage, max_len: usize) -> Option<Vec<u8>> {
+    match fit_response(&mut msg, max_len) {
+        Ok(data) => Some(data),
+        Err(e) => {
+            eprintln!("failed to encode response: {}", e);
+            fit_response(&mut error_response(msg, RCODE::ServerFailure), max_len).ok()
+        },
+    }
+}
+
+// Encodes a response, fitting it into what the client can receive. Additional records are left out first, since the answer is
+// complete without them. If that's not enough, the response is cut off after the last record that fits and TC is set, so a UDP
+// client knows to retry over TCP (RFC 2181 section 9).
+fn fit_response(msg: &mut DNSMessage, max_len: usize) -> Result<Vec<u8>, BuildError> {
+    let mut data = Vec::new();
+    let truncated = encode_message(&mut data, msg, max_len, true)?;
+
+    if !truncated.needs_tc() {
+        return Ok(data);
+    }
+
+    msg.header.tc = true;
+    data.clear();
+    encode_message(&mut data, msg, max_len, true)?;
+
+    Ok(data)
+}
+
+// Ways that forwarding a query can fail, which the client is told about with an Extended DNS Error
+#[derive(Debug, Error)]
+enum ForwardError {
+    #[error("failed to send data to forward server: {0}")]
+    Send(io::Error),
+
+    #[error("no response from forward server: {0}")]
+    Receive(io::Error),
+
+    #[error("forward server sent a malformed response: {0}")]
+    BadResponse(#[from] ParseError),
+
+    #[error("forward server's response is for a different query")]
+    WrongAnswer,
+
+    // Someone who didn't see the query sent the response, so it's likely spoofed
+    #[error("forward server's response has the wrong client cookie")]
+    WrongCookie,
+
+    #[error("failed to encode query for forward server: {0}")]
+    Encode(#[from] BuildError),
+
+    #[error("failed to get a random query ID: {0}")]
+    Random(#[from] getrandom::Error),
+}
+
+impl ForwardError {
+    fn extended_error(&self) -> ExtendedErrorCode {
+        match self {
+            ForwardError::Receive(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                ExtendedErrorCode::NoReachableAuthority
+            },
+            ForwardError::Send(_) | ForwardError::Receive(_) | ForwardError::WrongCookie => ExtendedErrorCode::NetworkError,
+            ForwardError::BadResponse(_) | ForwardError::WrongAnswer => ExtendedErrorCode::InvalidData,
+            ForwardError::Encode(_) | ForwardError::Random(_) => ExtendedErrorCode::OtherError,
+        }
+    }
+}
+
+fn forward_request(
+    msg: &DNSMessage,
+    source: SocketAddr,
+    forward_addr: SocketAddr,
+    timeout: Duration,
+    config: &Config,
+    upstream_cookie: &Mutex<UpstreamCookie>,
+) -> Result<DNSMessage, ForwardError>{
+    let mut builder = MessageBuilder::response(msg);
+
+    // The answer is only authentic if the upstream vouched for every question we asked it
+    let mut authentic = !msg.questions.is_empty();
+
+    let client_subnet = msg.edns.as_ref().and_then(|e| e.options.iter().find(|o| matches!(o, EdnsOption::ClientSubnet { .. })));
+    let (upstream_subnet, subnet_from_client) = upstream_client_subnet(client_subnet, source.ip(), config);
+
+    // The most specific scope any of the upstream answers was given for
+    let mut scope = 0;
+
+    // The first error from the upstream, along with any Extended DNS Errors explaining it
+    let mut rcode = RCODE::NoError;
+    let mut extended_errors: Vec<EdnsOption> = Vec::new();
+
+    for q in msg.questions.iter() {
+        // The client's CD bit is passed on, so a client doing its own validation still gets data the upstream couldn't validate.
+        // AD in a query just asks for the bit in the response (RFC 6840 section 5.7), so it's passed on too. The ID is picked by
+        // query_upstream, and the client's is the one the response is built with.
+        let query = MessageBuilder::query(0)
+            .rd(msg.header.rd)
+            .ad(msg.header.ad)
+            .cd(msg.header.cd)
+            .question(q.clone())
+            // Always ask for large responses, the answer gets cut down to the client's size afterwards
+            .edns(Some(Edns {
+                udp_payload_size: UDP_PAYLOAD_SIZE,
+                version: EDNS_VERSION,
+                dnssec_ok: msg.edns.as_ref().is_some_and(|e| e.dnssec_ok),
+                z: 0,
+                options: upstream_subnet.iter().cloned().collect(),
+            }))
+            .build();
+
+        let mut response = query_upstream(query, forward_addr, timeout, upstream_cookie)?;
+
+        // If there's an empty response, return an empty answer
+        if response.header.ancount == 0 {
+            response.answers = Vec::new();
+        }
+
+        authentic &= response.header.ad;
+
+        // BADCOOKIE and BADVERS are about how the forwarder talks to the upstream, which the client has nothing to do with
+        if rcode == RCODE::NoError {
+            rcode = match response.header.rcode {
+                RCODE::BadCookie | RCODE::BadVers => RCODE::ServerFailure,
+                upstream_rcode => upstream_rcode,
+            };
+        }
+
+        let response_options = response.edns.iter().flat_map(|e| e.options.iter());
+        for option in response_options {
+            match option {
+                EdnsOption::ClientSubnet { scope_prefix, .. } => scope = scope.max(*scope_prefix),
+                EdnsOption::ExtendedError { .. } => extended_errors.push(option.clone()),
+                _ => (),
+            }
+        }
+
+        builder = response.answers.into_iter().fold(builder, MessageBuilder::answer);
+        builder = response.authorities.into_iter().fold(builder, MessageBuilder::authority);
+        builder = response.additionals.into_iter().fold(builder, MessageBuilder::additional);
+    }
+
+    // A client that sent a subnet gets it back along with the scope of the answer (RFC 7871 section 7.2.2). If its subnet wasn't
+    // the one sent upstream, the answer doesn't depend on it at all.
+    let client_subnet = match client_subnet {
+        Some(EdnsOption::ClientSubnet { source_prefix, address, .. }) => Some(EdnsOption::ClientSubnet {
+            source_prefix: *source_prefix,
+            scope_prefix: if subnet_from_client { scope } else { 0 },
+            address: *address,
+        }),
+        _ => None,
+    };
+
+    let mut edns = response_edns(msg.edns.as_ref());
+    if let Some(edns) = edns.as_mut() {
+        edns.options.extend(client_subnet);
+        edns.options.extend(extended_errors);
+    }
+
+    Ok(builder.rcode(rcode).ad(authentic).edns(edns).build())
+}
+
+// Sends a query upstream with the forwarder's cookies added to it, and waits for the answer. If the upstream answers BADCOOKIE, the
+// query is sent once more with the server cookie from that answer (RFC 7873 section 5.3).
+//
+// Each query gets its own socket, so answers can't get mixed up between queries that are waiting at the same time. A datagram is only
+// taken as the answer if its ID, opcode and question match the query's (RFC 5452 section 9.1), and anything else that arrives is
+// dropped while waiting. A spoofed answer has to guess both the random ID and the random source port (RFC 5452 section 9.2).
+fn query_upstream(
+    mut query: DNSMessage,
+    forward_addr: SocketAddr,
+    timeout: Duration,
+    upstream_cookie: &Mutex<UpstreamCookie>,
+) -> Result<DNSMessage, ForwardError> {
+    let forward_conn = upstream_socket(forward_addr).map_err(ForwardError::Send)?;
+    let mut retried = false;
+
+    loop {
+        let (client_cookie, server_cookie) = {
+            let cookie = upstream_cookie.lock().unwrap();
+            (cookie.client, cookie.server.clone())
+        };
+
+        if let Some(edns) = query.edns.as_mut() {
+            edns.options.retain(|o| !matches!(o, EdnsOption::Cookie { .. }));
+            edns.options.push(EdnsOption::Cookie { client: client_cookie, server: server_cookie });
+        }
+
+        let mut id = [0; 2];
+        getrandom::getrandom(&mut id)?;
+        query.header.id = u16::from_be_bytes(id);
+
+        let message = build_message(query.clone())?;
+        forward_conn.send(&message).map_err(ForwardError::Send)?;
+        let mut response = receive_answer(&forward_conn, &query, timeout)?;
+
+        // An answer that didn't fit in a datagram is asked for again over TCP (RFC 7766 section 5), so clients get all of it.
+        // Passing the truncated one on would leave TCP clients with no way of getting the rest.
+        if response.header.tc {
+            response = query_upstream_tcp(&query, &message, forward_addr, timeout)?;
+        }
+
+        match cookie::find_cookie(response.edns.as_ref()) {
+            Some((client, server)) if client == client_cookie => upstream_cookie.lock().unwrap().server = server.to_vec(),
+            Some(_) => return Err(ForwardError::WrongCookie),
+            None => (), // The upstream doesn't support cookies
+        }
+
+        if response.header.rcode != RCODE::BadCookie || retried {
+            return Ok(response);
+        }
+
+        retried = true;
+    }
+}
+
+// Waits for the answer to a query on a socket connected to the forward server, dropping datagrams that aren't the answer until the
+// timeout runs out
+fn receive_answer(forward_conn: &UdpSocket, query: &DNSMessage, timeout: Duration) -> Result<DNSMessage, ForwardError> {
+    let deadline = Instant::now() + timeout;
+
+    // The upstream can answer with more than we advertised, and it's cut down to the client's size afterwards anyway
+    let mut buf = vec![0; MAX_MESSAGE_LEN];
+
+    loop {
+        // Each datagram that's dropped uses up some of the time, so a stream of them can't keep the query waiting forever
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(ForwardError::Receive(io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for the answer")));
+        }
+        forward_conn.set_read_timeout(Some(remaining)).map_err(ForwardError::Receive)?;
+
+        let bytes_received = forward_conn.recv(&mut buf).map_err(ForwardError::Receive)?;
+        let data = &buf[..bytes_received];
+
+        // Only a datagram with the query's ID can be from the upstream, so anything else is dropped without parsing it
+        if data.get(..2) != Some(&query.header.id.to_be_bytes()[..]) {
+            continue;
+        }
+
+        let response = parse_message(data)?;
+        if is_answer(query, &response) {
+            return Ok(response);
+        }
+    }
+}
+
+// Whether a response is the answer to a query (RFC 5452 section 9.1)
+fn is_answer(query: &DNSMessage, response: &DNSMessage) -> bool {
+    response.header.id == query.header.id
+        && response.header.qr == QR::Response
+        && response.header.opcode == query.header.opcode
+        && response.questions == query.questions
+}
+
+// Sends a query to the forward server over TCP and waits for the answer. A new connection is made each time, since answers too big
+// for UDP are rare.
+fn query_upstream_tcp(query: &DNSMessage, message: &[u8], forward_addr: SocketAddr, timeout: Duration) -> Result<DNSMessage, ForwardError> {
+    let mut stream = TcpStream::connect_timeout(&forward_addr, timeout).map_err(ForwardError::Send)?;
+    stream.set_read_timeout(Some(timeout)).map_err(ForwardError::Send)?;
+    stream.set_write_timeout(Some(timeout)).map_err(ForwardError::Send)?;
+
+    tcp::write_message(&mut stream, message).map_err(ForwardError::Send)?;
+
+    let response = match tcp::read_message(&mut stream).map_err(ForwardError::Receive)? {
+        Some(data) => parse_message(&data)?,
+        None => return Err(ForwardError::Receive(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before the answer"))),
+    };
+
+    // Nobody else can answer on the connection, so any other response means the upstream got something wrong
+    if !is_answer(query, &response) {
+        return Err(ForwardError::WrongAnswer);
+    }
+
+    Ok(response)
+}
+
+// A socket for talking to the forward server, bound to a random port
+fn upstream_socket(forward_addr: SocketAddr) -> io::Result<UdpSocket> {
+    let local_addr = match forward_addr {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+
+    let socket = UdpSocket::bind(local_addr)?;
+    socket.connect(forward_addr)?;
+
+    Ok(socket)
+}
+
+// Picks the EDNS Client Subnet option to send upstream, and whether it's the client's own subnet. A subnet sent by the client is
+// passed on unless privacy mode is on, shortened if it's more specific than the configured prefix length allows. Otherwise the
+// subnet is taken from the address the query came from, unless that address isn't global. A prefix length of 0 turns the option
+// off for that address family.
+//
+// Nothing is cached, so the scope the upstream answers with only needs to be passed back to the client.
+fn upstream_client_subnet(client_subnet: Option<&EdnsOption>, source: IpAddr, config: &Config) -> (Option<EdnsOption>, bool) {
+    let max_prefix = |address: &IpAddr| match address.to_canonical() {
+        IpAddr::V4(_) => config.ecs_prefix_v4,
+        IpAddr::V6(_) => config.ecs_prefix_v6,
+    };
+
+    let (address, source_prefix, from_client) = match client_subnet {
+        // A client that sends a /0 subnet is asking for no part of its address to be used (RFC 7871 section 7.1.2), so that's
+        // passed on as it is, even in privacy mode and even for the family that's turned off. A /0 subnet says nothing about anyone.
+        Some(EdnsOption::ClientSubnet { address, source_prefix: 0, .. }) => return (Some(EdnsOption::client_subnet(*address, 0)), true),
+
+        Some(EdnsOption::ClientSubnet { address, source_prefix, .. }) if !config.ecs_privacy => {
+            (*address, (*source_prefix).min(max_prefix(address)), true)
+        },
+
+        // The subnet of a loopback or private address says nothing about where the client is, and sending it would leak how the
+        // local network is laid out (RFC 7871 section 7.1.2)
+        _ if !is_global(source) => return (None, false),
+        _ => (source, max_prefix(&source), false),
+    };
+
+    if max_prefix(&address) == 0 {
+        return (None, false);
+    }
+
+    (Some(EdnsOption::client_subnet(address, source_prefix)), from_client)
+}
+
+// Whether an address can be reached from the internet, i.e. isn't loopback, private, link-local or set aside for some other use
+fn is_global(address: IpAddr) -> bool {
+    match address.to_canonical() {
+        IpAddr::V4(a) => {
+            let shared = a.octets()[0] == 100 && a.octets()[1] & 0b1100_0000 == 64; // Carrier-grade NAT (RFC 6598)
+
+            !(a.is_unspecified()
+                || a.is_loopback()
+                || a.is_private()
+                || a.is_link_local()
+                || a.is_broadcast()
+                || a.is_documentation()
+                || shared)
+        },
+
+        IpAddr::V6(a) => {
+            let [first, second, ..] = a.segments();
+            let unique_local = first & 0xfe00 == 0xfc00; // fc00::/7 (RFC 4193)
+            let link_local = first & 0xffc0 == 0xfe80; // fe80::/10
+            let documentation = first == 0x2001 && second == 0x0db8; // 2001:db8::/32 (RFC 3849)
+
+            !(a.is_unspecified() || a.is_loopback() || unique_local || link_local || documentation)
+        },
+    }
+}
+
+fn resolve_request(mut msg: DNSMessage) -> DNSMessage {
+    for q in msg.questions.iter_mut() {
+        q.qtype = types::RecordType::A;
+        q.qclass = types::ClassType::IN;
+    }
+
+    // Anything the client put in the other sections isn't part of the answer
+    let mut builder = MessageBuilder::response(&msg);
+
+    for q in msg.questions.iter() {
+        builder = builder.answer(ResourceRecord {
+            name: q.qname.to_owned(),
+            record_type: types::RecordType::A,
+            class: types::ClassType::IN,
+            ttl: 0,
+            rdata: RData::A(Ipv4Addr::new(192, 168, 0, 6)),
+        });
+    }
+
+    builder.edns(response_edns(msg.edns.as_ref())).build()
+}
+
+// Answers a query with no records and TC set, which tells the client to retry over TCP
+fn truncated_response(msg: DNSMessage) -> DNSMessage {
+    MessageBuilder::response(&msg).tc(true).edns(response_edns(msg.edns.as_ref())).build()
+}
+
+// Answers a query with just an error code
+fn error_response(msg: DNSMessage, rcode: RCODE) -> DNSMessage {
+    MessageBuilder::response(&msg).rcode(rcode).edns(response_edns(msg.edns.as_ref())).build()
+}
+
+// Answers a query with an error code, explaining it with an Extended DNS Error (RFC 8914) if the client supports EDNS
+fn extended_error_response(msg: DNSMessage, rcode: RCODE, info_code: ExtendedErrorCode, extra_text: String) -> DNSMessage {
+    let mut msg = error_response(msg, rcode);
+
+    if let Some(edns) = msg.edns.as_mut() {
+        edns.options.push(EdnsOption::ExtendedError { info_code, extra_text });
+    }
+
+    msg
+}
+
+// Answers a message that couldn't be fully decoded with a FORMERR, echoing whatever header and question data could be salvaged.
+// Returns None when the message should be dropped instead, either because not even the ID is readable or because it's a response.
+fn format_error_response(data: &[u8]) -> Option<DNSMessage> {
+    let partial = parse_partial(data)?;
+
+    let (opcode, rd) = match partial.header {
+        Some(h) if h.qr == QR::Response => return None, // Replying to responses could start a loop between two servers
+        Some(h) => (h.opcode, h.rd),
+        None => (Opcode::QUERY, false),
+    };
+
+    let header = DNSHeader {
+        id: partial.id,
+        qr: QR::Response,
+        opcode,
+        aa: false,
+        tc: false,
+        rd,
+        ra: false,
+        z: 0,
+        ad: false,
+        cd: false,
+        rcode: RCODE::FormatError,
+        qdcount: partial.questions.len() as u16,
+        ancount: 0,
+        nscount: 0,
+        arcount: 0,
+    };
+
+    // The OPT record may be what was malformed, so the response doesn't have one (RFC 6891 section 7)
+    Some(DNSMessage {
+        header,
+        questions: partial.questions,
+        answers: Vec::new(),
+        authorities: Vec::new(),
+        additionals: Vec::new(),
+        edns: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    use crate::types::{ClassType, DNSQuestion, Name, RecordType};
+
+    const CLIENT: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5353);
+
+    // A query for io. with an OPT record, so the response can have Extended DNS Errors
+    fn query(opcode: Opcode) -> Vec<u8> {
+        let mut query = MessageBuilder::query(1234)
+            .question(DNSQuestion {
+                qname: Name::from_wire(&[0x02, b'i', b'o', 0x00]).unwrap(),
+                qtype: RecordType::A,
+                qclass: ClassType::IN,
+            })
+            .edns(Some(Edns { udp_payload_size: 1232, version: 0, dnssec_ok: false, z: 0, options: Vec::new() }))
+            .build();
+        query.header.opcode = opcode;
+
+        build_message(query).unwrap()
+    }
+
+    // A forward server that answers every query with whatever `reply` makes of it
+    fn fake_upstream(reply: fn(&[u8]) -> Vec<u8>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((size, source)) = socket.recv_from(&mut buf) {
+                let _ = socket.send_to(&reply(&buf[..size]), source);
+            }
+        });
+
+        addr
+    }
+
+    // Starts a TCP server on the same port as a fake upstream, answering with whatever `reply` returns for each query it gets. It
+    // closes the connection instead of answering when `reply` returns None.
+    fn fake_tcp_upstream(addr: SocketAddr, reply: fn(&[u8]) -> Option<Vec<u8>>) {
+        let listener = TcpListener::bind(addr).unwrap();
+
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                while let Ok(Some(query)) = tcp::read_message(&mut stream) {
+                    match reply(&query) {
+                        Some(response) => tcp::write_message(&mut stream, &response).unwrap(),
+                        None => break,
+                    }
+                }
+            }
+        });
+    }
+
+    // Answers a query with 100 A records, which come to over 1600 bytes, more than the 1232 we advertise to the upstream
+    fn large_answer(data: &[u8]) -> Vec<u8> {
+        let query = parse_message(data).unwrap();
+        let answer = |i: u8| ResourceRecord {
+            name: query.questions[0].qname.clone(),
+            record_type: RecordType::A,
+            class: ClassType::IN,
+            ttl: 60,
+            rdata: RData::A(Ipv4Addr::new(10, 0, 0, i)),
+        };
+
+        build_message((0..100).map(answer).fold(MessageBuilder::response(&query), MessageBuilder::answer).build()).unwrap()
+    }
+
+    // Answers a query with nothing but the TC bit, the way an upstream does when the answer doesn't fit in a datagram
+    fn truncated_answer(data: &[u8]) -> Vec<u8> {
+        build_message(MessageBuilder::response(&parse_message(data).unwrap()).tc(true).build()).unwrap()
+    }
+
+    #[test]
+    fn test_response_size() {
+        struct Test {
+            label: String,
+            transport: Transport,
+            questions: usize,
+            udp_payload_size: Option<u16>, // From the query's OPT record, without one if None
+            want_len: usize,
+            want_answers: usize,
+            want_tc: bool,
+        }
+
+        // The questions are 20 bytes for the first and 13 for each one after, since their names are compressed, and each answer is 16
+        // bytes. The OPT record is 11 bytes.
+        let tests: Vec<Test> = vec![
+            Test {
+                label: "UDP without EDNS".to_string(),
+                transport: Transport::Udp,
+                questions: 10,
+                udp_payload_size: None,
+                want_len: 12 + 20 + 9 * 13 + 10 * 16,
+                want_answers: 10,
+                want_tc: false,
+            },
+
+            Test {
+                label: "UDP without EDNS over 512 bytes".to_string(),
+                transport: Transport::Udp,
+                questions: 20,
+                udp_payload_size: None,
+                want_len: 12 + 20 + 19 * 13 + 14 * 16,
+                want_answers: 14,
+                want_tc: true,
+            },
+
+            Test {
+                label: "UDP with EDNS over 512 bytes".to_string(),
+                transport: Transport::Udp,
+                questions: 20,
+                udp_payload_size: Some(1232),
+                want_len: 12 + 20 + 19 * 13 + 20 * 16 + 11,
+                want_answers: 20,
+                want_tc: false,
+            },
+
+            // Clients can ask for more, but never get more than we advertise
+            Test {
+                label: "UDP with EDNS over 1232 bytes".to_string(),
+                transport: Transport::Udp,
+                questions: 60,
+                udp_payload_size: Some(4096),
+                want_len: 12 + 20 + 59 * 13 + 26 * 16 + 11,
+                want_answers: 26,
+                want_tc: true,
+            },
+
+            Test {
+                label: "UDP with a payload size under 512 bytes".to_string(),
+                transport: Transport::Udp,
+                questions: 20,
+                udp_payload_size: Some(256),
+                want_len: 12 + 20 + 19 * 13 + 13 * 16 + 11, // Treated as 512, with the OPT record taking room from the answers
+                want_answers: 13,
+                want_tc: true,
+            },
+
+            Test {
+                label: "TCP".to_string(),
+                transport: Transport::Tcp,
+                questions: 60,
+                udp_payload_size: None,
+                want_len: 12 + 20 + 59 * 13 + 60 * 16,
+                want_answers: 60,
+                want_tc: false,
+            },
+        ];
+
+        let server = Server::new(Config::default()).unwrap();
+
+        for t in tests {
+            println!("Running test \"{}\"", t.label);
+            let question = |i: usize| DNSQuestion {
+                qname: format!("host{:02}.example", i).parse().unwrap(),
+                qtype: RecordType::A,
+                qclass: ClassType::IN,
+            };
+            let edns = |udp_payload_size: u16| Edns { udp_payload_size, version: 0, dnssec_ok: false, z: 0, options: Vec::new() };
+
+            let query = (0..t.questions)
+                .map(question)
+                .fold(MessageBuilder::query(1234), MessageBuilder::question)
+                .edns(t.udp_payload_size.map(edns))
+                .build();
+            let query = build_message(query).unwrap();
+
+            let response = match t.transport {
+                Transport::Udp => server.handle_udp(&query, CLIENT),
+                Transport::Tcp => server.handle_tcp(&query, CLIENT),
+            };
+            let response = response.unwrap();
+            assert_eq!(response.len(), t.want_len);
+
+            let response = parse_message(&response).unwrap();
+            assert_eq!(response.questions.len(), t.questions);
+            assert_eq!(response.answers.len(), t.want_answers);
+            assert_eq!(response.header.tc, t.want_tc);
+        }
+    }
+
+    #[test]
+    fn test_large_upstream_answer() {
+        let upstream = fake_upstream(large_answer);
+
+        let server = Server::new(Config { resolver: Some(upstream.to_string()), ..Config::default() }).unwrap();
+        let response = parse_message(&server.handle_tcp(&query(Opcode::QUERY), CLIENT).unwrap()).unwrap();
+
+        assert_eq!(response.header.rcode, RCODE::NoError);
+        assert_eq!(response.answers.len(), 100);
+    }
+
+    #[test]
+    fn test_upstream_answer_matching() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = upstream.local_addr().unwrap();
+
+        // Sends a few datagrams that aren't the answer before the one that is, each of which has to be dropped
+        let handle = thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (size, source) = upstream.recv_from(&mut buf).unwrap();
+            let query = parse_message(&buf[..size]).unwrap();
+            let answer = || MessageBuilder::response(&query).answer(ResourceRecord {
+                name: query.questions[0].qname.clone(),
+                record_type: RecordType::A,
+                class: ClassType::IN,
+                ttl: 60,
+                rdata: RData::A(Ipv4Addr::new(10, 0, 0, 1)),
+            });
+
+            let mut wrong_id = answer().build();
+            wrong_id.header.id = !query.header.id;
+
+            let mut wrong_question = answer().build();
+            wrong_question.questions[0].qname = "example.".parse().unwrap();
+
+            let mut wrong_opcode = answer().build();
+            wrong_opcode.header.opcode = Opcode::NOTIFY;
+
+            let not_a_response = MessageBuilder::query(query.header.id).question(query.questions[0].clone()).build();
+
+            for response in [wrong_id, wrong_question, wrong_opcode, not_a_response, answer().build()] {
+                upstream.send_to(&build_message(response).unwrap(), source).unwrap();
+            }
+        });
+
+        let server = Server::new(Config { resolver: Some(addr.to_string()), ..Config::default() }).unwrap();
+        let response = parse_message(&server.handle_udp(&query(Opcode::QUERY), CLIENT).unwrap()).unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(response.header.rcode, RCODE::NoError);
+        assert_eq!(response.answers.len(), 1);
+
+        // The client gets its own ID back, not the random one the upstream saw
+        assert_eq!(response.header.id, 1234);
+    }
+
+    #[test]
+    fn test_truncated_upstream_answer() {
+        let upstream = fake_upstream(truncated_answer);
+        fake_tcp_upstream(upstream, |data| Some(large_answer(data)));
+
+        let server = Server::new(Config { resolver: Some(upstream.to_string()), ..Config::default() }).unwrap();
+
+        // TCP clients get the whole answer
+        let response = parse_message(&server.handle_tcp(&query(Opcode::QUERY), CLIENT).unwrap()).unwrap();
+        assert!(!response.header.tc);
+        assert_eq!(response.answers.len(), 100);
+
+        // UDP clients get as much as fits, and the TC bit so they know to ask again over TCP
+        let response = parse_message(&server.handle_udp(&query(Opcode::QUERY), CLIENT).unwrap()).unwrap();
+        assert!(response.header.tc);
+        assert!(response.answers.len() < 100);
+    }
+
+    #[test]
+    fn test_extended_errors() {
+        struct Test {
+            label: String,
+            resolver: Option<SocketAddr>,
+            opcode: Opcode,
+            want_rcode: RCODE,
+            want_info_code: u16,
+        }
+
+        // Queries sent here are never read, so waiting for an answer times out
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let tests: Vec<Test> = vec![
+            Test {
+                label: "forward server times out".to_string(),
+                resolver: Some(silent.local_addr().unwrap()),
+                opcode: Opcode::QUERY,
+                want_rcode: RCODE::ServerFailure,
+                want_info_code: 22, // No Reachable Authority
+            },
+
+            Test {
+                label: "forward server answers with the wrong client cookie".to_string(),
+                resolver: Some(fake_upstream(|data| {
+                    let query = parse_message(data).unwrap();
+                    let (client, _) = cookie::find_cookie(query.edns.as_ref()).unwrap();
+
+                    let mut edns = response_edns(query.edns.as_ref()).unwrap();
+                    edns.options.push(EdnsOption::Cookie { client: client.map(|b| !b), server: vec![0; 8] });
+
+                    build_message(MessageBuilder::response(&query).edns(Some(edns)).build()).unwrap()
+                })),
+                opcode: Opcode::QUERY,
+                want_rcode: RCODE::ServerFailure,
+                want_info_code: 23, // Network Error
+            },
+
+            Test {
+                label: "forward server answers with the wrong ID".to_string(),
+                resolver: Some(fake_upstream(|data| {
+                    let mut response = MessageBuilder::response(&parse_message(data).unwrap()).build();
+                    response.header.id = !response.header.id;
+                    build_message(response).unwrap()
+                })),
+                opcode: Opcode::QUERY,
+                want_rcode: RCODE::ServerFailure,
+                want_info_code: 22, // No Reachable Authority, since the answer is dropped
+            },
+
+            Test {
+                label: "forward server closes the TCP connection".to_string(),
+                resolver: Some({
+                    let upstream = fake_upstream(truncated_answer);
+                    fake_tcp_upstream(upstream, |_| None);
+                    upstream
+                }),
+                opcode: Opcode::QUERY,
+                want_rcode: RCODE::ServerFailure,
+                want_info_code: 23, // Network Error
+            },
+
+            Test {
+                label: "forward server answers with a malformed response".to_string(),
+                resolver: Some(fake_upstream(|data| vec![data[0], data[1], 0xde, 0xad])), // With the query's ID, so it isn't dropped
+                opcode: Opcode::QUERY,
+                want_rcode: RCODE::ServerFailure,
+                want_info_code: 24, // Invalid Data
+            },
+
+            Test {
+                label: "opcode other than QUERY".to_string(),
+                resolver: None,
+                opcode: Opcode::NOTIFY,
+                want_rcode: RCODE::NotImplemented,
+                want_info_code: 21, // Not Supported
+            },
+        ];
+
+        for t in tests {
+            println!("Running test \"{}\"", t.label);
+            let mut server = Server::new(Config { resolver: t.resolver.map(|addr| addr.to_string()), ..Config::default() }).unwrap();
+            server.upstream_timeout = Duration::from_millis(100);
+
+            let response = parse_message(&server.handle_udp(&query(t.opcode), CLIENT).unwrap()).unwrap();
+            assert_eq!(response.header.rcode, t.want_rcode);
+
+            let info_codes: Vec<u16> = response.edns.unwrap().options.into_iter().filter_map(|o| match o {
+                EdnsOption::ExtendedError { info_code, .. } => Some(u16::from(info_code)),
+                _ => None,
+            }).collect();
+            assert_eq!(info_codes, vec![t.want_info_code]);
+        }
+    }
+
+    #[test]
+    fn test_forward_error_codes() {
+        struct Test {
+            label: String,
+            error: ForwardError,
+            want: ExtendedErrorCode,
+        }
+
+        let tests: Vec<Test> = vec![
+            Test {
+                label: "query can't be sent".to_string(),
+                error: ForwardError::Send(io::ErrorKind::PermissionDenied.into()),
+                want: ExtendedErrorCode::NetworkError,
+            },
+
+            Test {
+                label: "forward server refuses the query".to_string(),
+                error: ForwardError::Receive(io::ErrorKind::ConnectionRefused.into()),
+                want: ExtendedErrorCode::NetworkError,
+            },
+
+            Test {
+                label: "forward server times out".to_string(),
+                error: ForwardError::Receive(io::ErrorKind::WouldBlock.into()),
+                want: ExtendedErrorCode::NoReachableAuthority,
+            },
+
+            Test {
+                label: "forward server times out on Windows".to_string(),
+                error: ForwardError::Receive(io::ErrorKind::TimedOut.into()),
+                want: ExtendedErrorCode::NoReachableAuthority,
+            },
+
+            Test {
+                label: "forward server answers a different query over TCP".to_string(),
+                error: ForwardError::WrongAnswer,
+                want: ExtendedErrorCode::InvalidData,
+            },
+
+            Test {
+                label: "query can't be encoded".to_string(),
+                error: ForwardError::Encode(BuildError::NoRoom { needed: 13, max_len: 12 }),
+                want: ExtendedErrorCode::OtherError,
+            },
+        ];
+
+        for t in tests {
+            println!("Running test \"{}\"", t.label);
+            assert_eq!(t.error.extended_error(), t.want);
+        }
+    }
+
+    #[test]
+    fn test_upstream_client_subnet() {
+        struct Test {
+            label: String,
+            client_subnet: Option<EdnsOption>,
+            source: IpAddr,
+            config: Config,
+            want: (Option<EdnsOption>, bool),
+        }
+
+        let subnet = |address: &str, source_prefix: u8| EdnsOption::ClientSubnet {
+            source_prefix,
+            scope_prefix: 0,
+            address: address.parse().unwrap(),
+        };
+
+        let tests: Vec<Test> = vec![
+            Test {
+                label: "documentation source address".to_string(),
+                client_subnet: None,
+                source: "198.51.100.7".parse().unwrap(),
+                config: Config::default(),
+                want: (None, false),
+            },
+
+            Test {
+                label: "global IPv4 source address".to_string(),
+                client_subnet: None,
+                source: "8.8.4.4".parse().unwrap(),
+                config: Config::default(),
+                want: (Some(subnet("8.8.4.0", 24)), false),
+            },
+
+            Test {
+                label: "global IPv6 source address".to_string(),
+                client_subnet: None,
+                source: "2a00:1450:4001:80b::200e".parse().unwrap(),
+                config: Config::default(),
+                want: (Some(subnet("2a00:1450:4001:800::", 56)), false),
+            },
+
+            Test {
+                label: "IPv4-mapped IPv6 source address".to_string(),
+                client_subnet: None,
+                source: "::ffff:8.8.4.4".parse().unwrap(),
+                config: Config::default(),
+                want: (Some(subnet("8.8.4.0", 24)), false),
+            },
+
+            Test {
+                label: "loopback source address".to_string(),
+                client_subnet: None,
+                source: "127.0.0.1".parse().unwrap(),
+                config: Config::default(),
+                want: (None, false),
+            },
+
+            Test {
+                label: "private source address".to_string(),
+                client_subnet: None,
+                source: "10.1.2.3".parse().unwrap(),
+                config: Config::default(),
+                want: (None, false),
+            },
+
+            Test {
+                label: "carrier-grade NAT source address".to_string(),
+                client_subnet: None,
+                source: "100.64.0.1".parse().unwrap(),
+                config: Config::default(),
+                want: (None, false),
+            },
+
+            Test {
+                label: "unique local IPv6 source address".to_string(),
+                client_subnet: None,
+                source: "fd12:3456::1".parse().unwrap(),
+                config: Config::default(),
+                want: (None, false),
+            },
+
+            Test {
+                label: "link-local IPv6 source address".to_string(),
+                client_subnet: None,
+                source: "fe80::1".parse().unwrap(),
+                config: Config::default(),
+                want: (None, false),
+            },
+
+            Test {
+                label: "client subnet passed on".to_string(),
+                client_subnet: Some(subnet("203.0.0.0", 16)),
+                source: "127.0.0.1".parse().unwrap(),
+                config: Config::default(),
+                want: (Some(subnet("203.0.0.0", 16)), true),
+            },
+
+            Test {
+                label: "client subnet shortened to the configured prefix length".to_string(),
+                client_subnet: Some(subnet("1.2.3.4", 32)),
+                source: "127.0.0.1".parse().unwrap(),
+                config: Config::default(),
+                want: (Some(subnet("1.2.3.0", 24)), true),
+            },
+
+            Test {
+                label: "client subnet replaced in privacy mode".to_string(),
+                client_subnet: Some(subnet("1.2.3.0", 24)),
+                source: "8.8.4.4".parse().unwrap(),
+                config: Config { ecs_privacy: true, ..Config::default() },
+                want: (Some(subnet("8.8.4.0", 24)), false),
+            },
+
+            Test {
+                label: "client opted out".to_string(),
+                client_subnet: Some(subnet("0.0.0.0", 0)),
+                source: "8.8.4.4".parse().unwrap(),
+                config: Config::default(),
+                want: (Some(subnet("0.0.0.0", 0)), true),
+            },
+
+            Test {
+                label: "client opted out in privacy mode".to_string(),
+                client_subnet: Some(subnet("0.0.0.0", 0)),
+                source: "8.8.4.4".parse().unwrap(),
+                config: Config { ecs_privacy: true, ..Config::default() },
+                want: (Some(subnet("0.0.0.0", 0)), true),
+            },
+
+            Test {
+                label: "client opted out with an address".to_string(),
+                client_subnet: Some(subnet("2a00:1450::", 0)),
+                source: "2a00:1450:4001:80b::200e".parse().unwrap(),
+                config: Config { ecs_privacy: true, ..Config::default() },
+                want: (Some(subnet("::", 0)), true),
+            },
+
+            Test {
+                label: "client opted out with IPv4 turned off".to_string(),
+                client_subnet: Some(subnet("0.0.0.0", 0)),
+                source: "8.8.4.4".parse().unwrap(),
+                config: Config { ecs_prefix_v4: 0, ..Config::default() },
+                want: (Some(subnet("0.0.0.0", 0)), true),
+            },
+
+            Test {
+                label: "IPv4 turned off".to_string(),
+                client_subnet: Some(subnet("1.2.3.0", 24)),
+                source: "8.8.4.4".parse().unwrap(),
+                config: Config { ecs_prefix_v4: 0, ..Config::default() },
+                want: (None, false),
+            },
+
+            Test {
+                label: "IPv4 turned off but not IPv6".to_string(),
+                client_subnet: None,
+                source: "2a00:1450:4001:80b::200e".parse().unwrap(),
+                config: Config { ecs_prefix_v4: 0, ecs_prefix_v6: 32, ..Config::default() },
+                want: (Some(subnet("2a00:1450::", 32)), false),
+            },
+        ];
+
+        for t in tests {
+            println!("Running test \"{}\"", t.label);
+            assert_eq!(upstream_client_subnet(t.client_subnet.as_ref(), t.source, &t.config), t.want);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+// How long to spend connecting to a TCP listener to wake it up. It's on this machine, so anything longer means it isn't listening.
+const WAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Keeps track of whether the server has been asked to stop, and of the TCP connections it has open so they can be stopped too
+pub struct Shutdown {
+    requested: AtomicBool,
+    connections: Mutex<Connections>,
+}
+
+struct Connections {
+    next_id: u64,
+    streams: HashMap<u64, TcpStream>,
+}
+
+/// A TCP connection being served. It stops being tracked when this is dropped.
+pub struct Connection<'a> {
+    shutdown: &'a Shutdown,
+    id: u64,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown {
+            requested: AtomicBool::new(false),
+            connections: Mutex::new(Connections { next_id: 0, streams: HashMap::new() }),
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Starts tracking a TCP connection that was just accepted. Returns None if the server is shutting down or already has `max`
+    /// connections open, in which case the connection should be closed instead of served.
+    pub fn track(&self, stream: &TcpStream, max: usize) -> io::Result<Option<Connection<'_>>> {
+        // The flag is checked while holding the lock, so a connection accepted while `request` runs is either turned away here or
+        // stopped by `request`
+        let mut connections = self.connections.lock().unwrap();
+        if self.is_requested() || connections.streams.len() >= max {
+            return Ok(None);
+        }
+
+        let id = connections.next_id;
+        connections.next_id += 1;
+        connections.streams.insert(id, stream.try_clone()?);
+
+        Ok(Some(Connection { shutdown: self, id }))
+    }
+
+    /// Asks the server to stop taking queries. The UDP sockets and TCP listeners bound to the given addresses are woken up so the
+    /// threads waiting on them notice, and the TCP connections being served are closed for reading, so each one answers the
+    /// queries it already has and then closes.
+    pub fn request(&self, udp_addrs: &[SocketAddr], tcp_addrs: &[SocketAddr]) {
+        self.requested.store(true, Ordering::SeqCst);
+
+        for stream in self.connections.lock().unwrap().streams.values() {
+            let _ = stream.shutdown(net::Shutdown::Read);
+        }
+
+        // Failing to wake a socket only means its thread isn't waiting on it anymore
+        for addr in udp_addrs {
+            let _ = wake_udp(*addr);
+        }
+
+        for addr in tcp_addrs {
+            let _ = TcpStream::connect_timeout(&reachable(*addr), WAKE_TIMEOUT);
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
+
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        self.shutdown.connections.lock().unwrap().streams.remove(&self.id);
+    }
+}
+
+// Sends an empty datagram to a UDP socket, so a thread waiting to receive from it gets something
+fn wake_udp(addr: SocketAddr) -> io::Result<()> {
+    let unspecified = match addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+
+    UdpSocket::bind((unspecified, 0))?.send_to(&[], reachable(addr))?;
+    Ok(())
+}
+
+// A socket bound to every address, e.g. 0.0.0.0, is reached through loopback
+fn reachable(mut addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        });
+    }
+
+    addr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Read, net::TcpListener, thread};
+
+    #[test]
+    fn test_track() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let _clients: Vec<TcpStream> = (0..3).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        let streams: Vec<TcpStream> = (0..3).map(|_| listener.accept().unwrap().0).collect();
+
+        let shutdown = Shutdown::new();
+        let first = shutdown.track(&streams[0], 2).unwrap();
+        assert!(first.is_some());
+        assert!(shutdown.track(&streams[1], 2).unwrap().is_some()); // Dropped straight away
+        let second = shutdown.track(&streams[1], 2).unwrap();
+        assert!(second.is_some());
+
+        // Only two connections can be open at once
+        assert!(shutdown.track(&streams[2], 2).unwrap().is_none());
+
+        drop(first);
+        assert!(shutdown.track(&streams[2], 2).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_request() {
+        let udp_socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let udp_addr = udp_socket.local_addr().unwrap();
+        let tcp_addr = listener.local_addr().unwrap();
+
+        let _client = TcpStream::connect(tcp_addr).unwrap();
+        let mut stream = listener.accept().unwrap().0;
+
+        let shutdown = Shutdown::new();
+        let _connection = shutdown.track(&stream, 1).unwrap().unwrap();
+        assert!(!shutdown.is_requested());
+
+        // Each of these would wait forever if the request didn't wake it
+        thread::scope(|s| {
+            s.spawn(|| udp_socket.recv_from(&mut [0; 512]).unwrap());
+            s.spawn(|| listener.accept().unwrap());
+            s.spawn(|| assert_eq!(stream.read(&mut [0; 512]).unwrap(), 0));
+
+            shutdown.request(&[udp_addr], &[tcp_addr]);
+        });
+
+        assert!(shutdown.is_requested());
+
+        let _late_client = TcpStream::connect(tcp_addr).unwrap();
+        let late_stream = listener.accept().unwrap().0;
+        assert!(shutdown.track(&late_stream, 2).unwrap().is_none());
+    }
+}
@@ -0,0 +1,168 @@
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Whether an error from receiving on a socket or accepting a connection only affects one datagram or connection, so the server can
+/// keep using the socket. Anything else means the socket itself is broken. Errors from sending are never treated as the socket
+/// being broken, see `send_response`.
+pub fn is_transient(e: &io::Error) -> bool {
+    match e.kind() {
+        // A UDP socket gets ECONNREFUSED when an earlier response was answered with ICMP port unreachable, e.g. because the client
+        // had already gone. Over TCP these come from one connection going wrong.
+        io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::Interrupted => true,
+
+        // The kernel ran out of buffers or memory, or the client can't be reached right now, which goes away by itself. Running out
+        // of file descriptors stops new TCP connections from being accepted until some are closed.
+        //
+        // EPERM and EMSGSIZE aren't here. Receiving or accepting doesn't fail with them because of anything one client did.
+        _ => matches!(
+            e.raw_os_error(),
+            Some(libc::ENOBUFS | libc::ENOMEM | libc::EHOSTUNREACH | libc::ENETUNREACH | libc::EMFILE | libc::ENFILE)
+        ),
+    }
+}
+
+/// Sends a response to a UDP client. Any error is counted and the server carries on, since the client picks where the response goes.
+/// A client can give a source address that can't be sent to, e.g. port 0, which fails with EINVAL, or one a firewall rule denies,
+/// which fails with EPERM. Neither says anything about the socket, and stopping the server over them would let any client stop it.
+pub fn send_response(socket: &UdpSocket, response: &[u8], target: SocketAddr, errors: &ErrorCounter) {
+    if let Err(e) = socket.send_to(response, target) {
+        errors.record(&format!("failed to send response to {}", target), &e);
+    }
+}
+
+/// Counts the socket errors the server has carried on after
+pub struct ErrorCounter {
+    count: AtomicU64,
+}
+
+impl ErrorCounter {
+    pub fn new() -> Self {
+        ErrorCounter { count: AtomicU64::new(0) }
+    }
+
+    /// Counts an error, and logs it along with the count so far. Only the 1st, 2nd, 4th, 8th and so on are logged, so a flood of
+    /// errors doesn't become a flood of log lines too.
+    pub fn record(&self, context: &str, e: &io::Error) {
+        let count = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if count.is_power_of_two() {
+            eprintln!("{}: {} ({} socket errors so far)", context, e, count);
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for ErrorCounter {
+    fn default() -> Self {
+        ErrorCounter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_transient() {
+        struct Test {
+            label: String,
+            error: io::Error,
+            want: bool,
+        }
+
+        let tests: Vec<Test> = vec![
+            Test {
+                label: "connection refused".to_string(),
+                error: io::Error::from_raw_os_error(libc::ECONNREFUSED),
+                want: true,
+            },
+
+            Test {
+                label: "would block".to_string(),
+                error: io::Error::from_raw_os_error(libc::EAGAIN),
+                want: true,
+            },
+
+            Test {
+                label: "no buffer space".to_string(),
+                error: io::Error::from_raw_os_error(libc::ENOBUFS),
+                want: true,
+            },
+
+            Test {
+                label: "too many open files".to_string(),
+                error: io::Error::from_raw_os_error(libc::EMFILE),
+                want: true,
+            },
+
+            Test {
+                label: "denied by a firewall".to_string(),
+                error: io::Error::from_raw_os_error(libc::EPERM),
+                want: false,
+            },
+
+            Test {
+                label: "message too long".to_string(),
+                error: io::Error::from_raw_os_error(libc::EMSGSIZE),
+                want: false,
+            },
+
+            Test {
+                label: "bad file descriptor".to_string(),
+                error: io::Error::from_raw_os_error(libc::EBADF),
+                want: false,
+            },
+
+            Test {
+                label: "not an OS error".to_string(),
+                error: io::Error::other("something else"),
+                want: false,
+            },
+        ];
+
+        for t in tests {
+            println!("Running test \"{}\"", t.label);
+            assert_eq!(is_transient(&t.error), t.want);
+        }
+    }
+
+    #[test]
+    fn test_send_response() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let errors = ErrorCounter::new();
+
+        // A datagram can claim to come from port 0, but nothing can be sent back to it
+        send_response(&socket, &[1, 2, 3], "127.0.0.1:0".parse().unwrap(), &errors);
+        assert_eq!(errors.count(), 1);
+
+        // The socket still works for everyone else
+        send_response(&socket, &[1, 2, 3], client.local_addr().unwrap(), &errors);
+        assert_eq!(errors.count(), 1);
+
+        let mut buf = [0; 512];
+        assert_eq!(client.recv(&mut buf).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_error_counter() {
+        let counter = ErrorCounter::new();
+
+        for _ in 0..5 {
+            counter.record("test", &io::Error::from_raw_os_error(libc::ENOBUFS));
+        }
+
+        assert_eq!(counter.count(), 5);
+    }
+}
@@ -0,0 +1,112 @@
+use std::io::{self, Read, Write};
+
+/// Reads a message from a TCP stream, where each one comes after its length as a 16-bit integer (RFC 1035 section 4.2.2). Returns
+/// None once the client has closed the connection between messages, and an UnexpectedEof error if it closed it partway through one.
+pub fn read_message(stream: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut length = [0; 2];
+    let mut length_read = 0;
+
+    // read_exact can't tell a connection closed before the length from one closed halfway through it
+    while length_read < length.len() {
+        match stream.read(&mut length[length_read..]) {
+            Ok(0) if length_read == 0 => return Ok(None),
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed in the middle of a message length")),
+            Ok(n) => length_read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    let mut message = vec![0; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut message)?;
+
+    Ok(Some(message))
+}
+
+/// Writes a message to a TCP stream along with its length. The two are written together, so a client never sees a length without
+/// the message that goes with it.
+pub fn write_message(stream: &mut impl Write, message: &[u8]) -> io::Result<()> {
+    let length = u16::try_from(message.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message is too long for TCP"))?;
+
+    let mut frame: Vec<u8> = Vec::with_capacity(2 + message.len());
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(message);
+
+    stream.write_all(&frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_message() {
+        struct Test {
+            label: String,
+            data: Vec<u8>,
+            want: Vec<Option<Vec<u8>>>,
+            want_err: Option<io::ErrorKind>, // After the messages in `want`
+        }
+
+        let tests: Vec<Test> = vec![
+            Test {
+                label: "one message".to_string(),
+                data: vec![0x00, 0x03, 1, 2, 3],
+                want: vec![Some(vec![1, 2, 3]), None],
+                want_err: None,
+            },
+
+            Test {
+                label: "pipelined messages".to_string(),
+                data: vec![0x00, 0x01, 1, 0x00, 0x02, 2, 3],
+                want: vec![Some(vec![1]), Some(vec![2, 3]), None],
+                want_err: None,
+            },
+
+            Test {
+                label: "closed straight away".to_string(),
+                data: vec![],
+                want: vec![None],
+                want_err: None,
+            },
+
+            Test {
+                label: "closed in the middle of a message".to_string(),
+                data: vec![0x00, 0x01, 1, 0x00, 0x03, 2, 3],
+                want: vec![Some(vec![1])],
+                want_err: Some(io::ErrorKind::UnexpectedEof),
+            },
+
+            Test {
+                label: "closed in the middle of a length".to_string(),
+                data: vec![0x00, 0x01, 1, 0x00],
+                want: vec![Some(vec![1])],
+                want_err: Some(io::ErrorKind::UnexpectedEof),
+            },
+        ];
+
+        for t in tests {
+            println!("Running test \"{}\"", t.label);
+            let mut stream = io::Cursor::new(t.data);
+
+            for want in t.want {
+                assert_eq!(read_message(&mut stream).unwrap(), want);
+            }
+
+            if let Some(kind) = t.want_err {
+                assert_eq!(read_message(&mut stream).unwrap_err().kind(), kind);
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_message() {
+        let mut stream: Vec<u8> = Vec::new();
+        write_message(&mut stream, &[1, 2, 3]).unwrap();
+        write_message(&mut stream, &[]).unwrap();
+        assert_eq!(stream, vec![0x00, 0x03, 1, 2, 3, 0x00, 0x00]);
+
+        let too_long = vec![0; u16::MAX as usize + 1];
+        assert_eq!(write_message(&mut stream, &too_long).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -1,10 +1,20 @@
-#[derive(Clone)]
+use std::{fmt, hash::{Hash, Hasher}, net::{IpAddr, Ipv4Addr, Ipv6Addr}, str::FromStr};
+
+use thiserror::Error;
+
+/// A decoded message. The header counts only need to match the sections for messages that were parsed, since the encoder works
+/// them out itself.
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub struct DNSMessage {
     pub header: DNSHeader,
     pub questions: Vec<DNSQuestion>,
     pub answers: Vec<ResourceRecord>,
+    pub authorities: Vec<ResourceRecord>,
+    pub additionals: Vec<ResourceRecord>,
+    pub edns: Option<Edns>, // The OPT record, which is sent in the additional section but isn't counted in `additionals`
 }
 
+/// The fixed 12 byte header at the start of every message (RFC 1035 section 4.1.1)
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub struct DNSHeader {
     pub id: u16,
@@ -14,8 +24,10 @@ pub struct DNSHeader {
     pub tc: bool, // 1 bit
     pub rd: bool, // 1 bit
     pub ra: bool, // 1 bit
-    pub z: u8, // 3 bits
-    pub rcode: RCODE, // 4 bits
+    pub z: u8, // 1 bit, reserved
+    pub ad: bool, // 1 bit, authentic data (RFC 4035)
+    pub cd: bool, // 1 bit, checking disabled (RFC 4035)
+    pub rcode: RCODE, // 4 bits, or 12 with the OPT record
     pub qdcount: u16,
     pub ancount: u16,
     pub nscount: u16,
@@ -28,14 +40,49 @@ pub enum QR {
     Response,
 }
 
+#[allow(clippy::upper_case_acronyms)] // Names follow the RFC 1035 mnemonics
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum Opcode {
     QUERY,
     IQUERY,
     STATUS,
-    Reserved,
+    NOTIFY, // RFC 1996
+    UPDATE, // RFC 2136
+    DSO, // RFC 8490
+    Unknown(u8),
+}
+
+impl From<u8> for Opcode {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Opcode::QUERY,
+            1 => Opcode::IQUERY,
+            2 => Opcode::STATUS,
+            4 => Opcode::NOTIFY,
+            5 => Opcode::UPDATE,
+            6 => Opcode::DSO,
+            _ => Opcode::Unknown(value),
+        }
+    }
+}
+
+impl From<Opcode> for u8 {
+    fn from(value: Opcode) -> Self {
+        match value {
+            Opcode::QUERY => 0,
+            Opcode::IQUERY => 1,
+            Opcode::STATUS => 2,
+            Opcode::NOTIFY => 4,
+            Opcode::UPDATE => 5,
+            Opcode::DSO => 6,
+            Opcode::Unknown(value) => value,
+        }
+    }
 }
 
+/// The header only has room for the lower 4 bits of an RCODE. The upper 8 bits of the extended RCODEs (16 and up) are carried in the
+/// OPT record (RFC 6891 section 6.1.3), and parse_message/build_message combine and split the two parts.
+#[allow(clippy::upper_case_acronyms)] // Names follow the RFC 1035 mnemonics
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum RCODE {
     NoError,
@@ -44,34 +91,1026 @@ pub enum RCODE {
     NameError,
     NotImplemented,
     Refused,
-    Reserved,
+    YXDomain,
+    YXRRSet,
+    NXRRSet,
+    NotAuth,
+    NotZone,
+    DSOTypeNI,
+    BadVers,
+    BadSig, // Shares 16 with BADVERS, but only appears in TSIG records, so 16 is always read back as BadVers
+    BadKey,
+    BadTime,
+    BadMode,
+    BadName,
+    BadAlg,
+    BadTrunc,
+    BadCookie,
+    Unknown(u16),
+}
+
+impl From<u16> for RCODE {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => RCODE::NoError,
+            1 => RCODE::FormatError,
+            2 => RCODE::ServerFailure,
+            3 => RCODE::NameError,
+            4 => RCODE::NotImplemented,
+            5 => RCODE::Refused,
+            6 => RCODE::YXDomain,
+            7 => RCODE::YXRRSet,
+            8 => RCODE::NXRRSet,
+            9 => RCODE::NotAuth,
+            10 => RCODE::NotZone,
+            11 => RCODE::DSOTypeNI,
+            16 => RCODE::BadVers,
+            17 => RCODE::BadKey,
+            18 => RCODE::BadTime,
+            19 => RCODE::BadMode,
+            20 => RCODE::BadName,
+            21 => RCODE::BadAlg,
+            22 => RCODE::BadTrunc,
+            23 => RCODE::BadCookie,
+            _ => RCODE::Unknown(value),
+        }
+    }
 }
 
+impl From<RCODE> for u16 {
+    fn from(value: RCODE) -> Self {
+        match value {
+            RCODE::NoError => 0,
+            RCODE::FormatError => 1,
+            RCODE::ServerFailure => 2,
+            RCODE::NameError => 3,
+            RCODE::NotImplemented => 4,
+            RCODE::Refused => 5,
+            RCODE::YXDomain => 6,
+            RCODE::YXRRSet => 7,
+            RCODE::NXRRSet => 8,
+            RCODE::NotAuth => 9,
+            RCODE::NotZone => 10,
+            RCODE::DSOTypeNI => 11,
+            RCODE::BadVers => 16,
+            RCODE::BadSig => 16,
+            RCODE::BadKey => 17,
+            RCODE::BadTime => 18,
+            RCODE::BadMode => 19,
+            RCODE::BadName => 20,
+            RCODE::BadAlg => 21,
+            RCODE::BadTrunc => 22,
+            RCODE::BadCookie => 23,
+            RCODE::Unknown(value) => value,
+        }
+    }
+}
+
+/// An entry in the question section
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct DNSQuestion {
-    pub qname: Vec<u8>,
+    pub qname: Name,
     pub qtype: RecordType,
     pub qclass: ClassType,
 }
 
+/// A record in the answer, authority or additional section
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct ResourceRecord {
-    pub name: Vec<u8>,
+    pub name: Name,
     pub record_type: RecordType,
     pub class: ClassType,
     pub ttl: u32,
-    pub rdlength: u16,
-    pub rdata: Vec<u8>,
+    pub rdata: RData,
 }
 
-#[derive(PartialEq, Eq, Debug, Clone, Copy)]
-pub enum RecordType {
-    A,
-    Other,
+/// Text fields hold the raw bytes of a `<character-string>` without its length prefix. Record types without a variant here are carried
+/// through as their raw bytes.
+#[allow(clippy::upper_case_acronyms)] // Names follow the IANA mnemonics
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum RData {
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    NS(Name),
+    CNAME(Name),
+    PTR(Name),
+    DNAME(Name),
+    MX {
+        preference: u16,
+        exchange: Name,
+    },
+    SOA {
+        mname: Name,
+        rname: Name,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    TXT(Vec<Vec<u8>>), // One entry per <character-string>, so each can be at most 255 bytes
+    SRV {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: Name,
+    },
+    CAA {
+        flags: u8,
+        tag: Vec<u8>,
+        value: Vec<u8>,
+    },
+    NAPTR {
+        order: u16,
+        preference: u16,
+        flags: Vec<u8>,
+        services: Vec<u8>,
+        regexp: Vec<u8>,
+        replacement: Name,
+    },
+    Unknown {
+        rtype: RecordType,
+        bytes: Vec<u8>,
+    },
+}
+
+/// The contents of an OPT pseudo-record (RFC 6891). The upper bits of an extended RCODE also travel in the OPT record, but they're
+/// combined into `DNSHeader.rcode` instead of being kept here.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Edns {
+    pub udp_payload_size: u16, // Stored in the CLASS field
+    pub version: u8,
+    pub dnssec_ok: bool, // 1 bit (RFC 3225)
+    pub z: u16, // 15 bits, reserved
+    pub options: Vec<EdnsOption>,
 }
 
+// EDNS option codes from the IANA registry
+pub const OPTION_CLIENT_SUBNET: u16 = 8; // RFC 7871
+pub const OPTION_COOKIE: u16 = 10; // RFC 7873
+pub const OPTION_EXTENDED_ERROR: u16 = 15; // RFC 8914
+
+/// Options without a variant here are carried through as their raw bytes, like unknown record types
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum EdnsOption {
+    // The network a query came from. The address family is sent as its own field, and only the first `source_prefix` bits of the
+    // address are sent, the rest must be zero.
+    ClientSubnet {
+        source_prefix: u8,
+        scope_prefix: u8, // How much of the subnet the answer depends on, always 0 in queries
+        address: IpAddr,
+    },
+    // The server cookie is empty when the client doesn't have one yet, otherwise it's 8 to 32 bytes long
+    Cookie {
+        client: [u8; 8],
+        server: Vec<u8>,
+    },
+    // Why a query failed, for people debugging it. The text is optional.
+    ExtendedError {
+        info_code: ExtendedErrorCode,
+        extra_text: String,
+    },
+    Unknown {
+        code: u16,
+        data: Vec<u8>,
+    },
+}
+
+impl EdnsOption {
+    /// Makes a client subnet option for the network `address` is in, keeping only the first `source_prefix` bits of the address.
+    /// The prefix is capped at the length of the address.
+    pub fn client_subnet(address: IpAddr, source_prefix: u8) -> Self {
+        let (address, source_prefix) = match address.to_canonical() {
+            IpAddr::V4(a) => {
+                let prefix = source_prefix.min(32);
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                (IpAddr::V4(Ipv4Addr::from(u32::from(a) & mask)), prefix)
+            },
+
+            IpAddr::V6(a) => {
+                let prefix = source_prefix.min(128);
+                let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                (IpAddr::V6(Ipv6Addr::from(u128::from(a) & mask)), prefix)
+            },
+        };
+
+        EdnsOption::ClientSubnet { source_prefix, scope_prefix: 0, address }
+    }
+
+    pub fn code(&self) -> u16 {
+        match self {
+            EdnsOption::ClientSubnet { .. } => OPTION_CLIENT_SUBNET,
+            EdnsOption::Cookie { .. } => OPTION_COOKIE,
+            EdnsOption::ExtendedError { .. } => OPTION_EXTENDED_ERROR,
+            EdnsOption::Unknown { code, .. } => *code,
+        }
+    }
+}
+
+/// Info codes for Extended DNS Errors (RFC 8914 section 4)
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
-pub enum ClassType {
-    IN,
-    Other,
-}
\ No newline at end of file
+pub enum ExtendedErrorCode {
+    OtherError,
+    UnsupportedDnskeyAlgorithm,
+    UnsupportedDsDigestType,
+    StaleAnswer,
+    ForgedAnswer,
+    DnssecIndeterminate,
+    DnssecBogus,
+    SignatureExpired,
+    SignatureNotYetValid,
+    DnskeyMissing,
+    RrsigsMissing,
+    NoZoneKeyBitSet,
+    NsecMissing,
+    CachedError,
+    NotReady,
+    Blocked,
+    Censored,
+    Filtered,
+    Prohibited,
+    StaleNxdomainAnswer,
+    NotAuthoritative,
+    NotSupported,
+    NoReachableAuthority,
+    NetworkError,
+    InvalidData,
+    Unknown(u16),
+}
+
+impl From<u16> for ExtendedErrorCode {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => ExtendedErrorCode::OtherError,
+            1 => ExtendedErrorCode::UnsupportedDnskeyAlgorithm,
+            2 => ExtendedErrorCode::UnsupportedDsDigestType,
+            3 => ExtendedErrorCode::StaleAnswer,
+            4 => ExtendedErrorCode::ForgedAnswer,
+            5 => ExtendedErrorCode::DnssecIndeterminate,
+            6 => ExtendedErrorCode::DnssecBogus,
+            7 => ExtendedErrorCode::SignatureExpired,
+            8 => ExtendedErrorCode::SignatureNotYetValid,
+            9 => ExtendedErrorCode::DnskeyMissing,
+            10 => ExtendedErrorCode::RrsigsMissing,
+            11 => ExtendedErrorCode::NoZoneKeyBitSet,
+            12 => ExtendedErrorCode::NsecMissing,
+            13 => ExtendedErrorCode::CachedError,
+            14 => ExtendedErrorCode::NotReady,
+            15 => ExtendedErrorCode::Blocked,
+            16 => ExtendedErrorCode::Censored,
+            17 => ExtendedErrorCode::Filtered,
+            18 => ExtendedErrorCode::Prohibited,
+            19 => ExtendedErrorCode::StaleNxdomainAnswer,
+            20 => ExtendedErrorCode::NotAuthoritative,
+            21 => ExtendedErrorCode::NotSupported,
+            22 => ExtendedErrorCode::NoReachableAuthority,
+            23 => ExtendedErrorCode::NetworkError,
+            24 => ExtendedErrorCode::InvalidData,
+            _ => ExtendedErrorCode::Unknown(value),
+        }
+    }
+}
+
+impl From<ExtendedErrorCode> for u16 {
+    fn from(value: ExtendedErrorCode) -> Self {
+        match value {
+            ExtendedErrorCode::OtherError => 0,
+            ExtendedErrorCode::UnsupportedDnskeyAlgorithm => 1,
+            ExtendedErrorCode::UnsupportedDsDigestType => 2,
+            ExtendedErrorCode::StaleAnswer => 3,
+            ExtendedErrorCode::ForgedAnswer => 4,
+            ExtendedErrorCode::DnssecIndeterminate => 5,
+            ExtendedErrorCode::DnssecBogus => 6,
+            ExtendedErrorCode::SignatureExpired => 7,
+            ExtendedErrorCode::SignatureNotYetValid => 8,
+            ExtendedErrorCode::DnskeyMissing => 9,
+            ExtendedErrorCode::RrsigsMissing => 10,
+            ExtendedErrorCode::NoZoneKeyBitSet => 11,
+            ExtendedErrorCode::NsecMissing => 12,
+            ExtendedErrorCode::CachedError => 13,
+            ExtendedErrorCode::NotReady => 14,
+            ExtendedErrorCode::Blocked => 15,
+            ExtendedErrorCode::Censored => 16,
+            ExtendedErrorCode::Filtered => 17,
+            ExtendedErrorCode::Prohibited => 18,
+            ExtendedErrorCode::StaleNxdomainAnswer => 19,
+            ExtendedErrorCode::NotAuthoritative => 20,
+            ExtendedErrorCode::NotSupported => 21,
+            ExtendedErrorCode::NoReachableAuthority => 22,
+            ExtendedErrorCode::NetworkError => 23,
+            ExtendedErrorCode::InvalidData => 24,
+            ExtendedErrorCode::Unknown(value) => value,
+        }
+    }
+}
+
+// Labels are limited to 63 bytes, and whole names to 255 bytes in wire format (RFC 1035 section 2.3.4)
+const MAX_LABEL_LEN: usize = 63;
+const MAX_NAME_LEN: usize = 255;
+
+/// A domain name, kept in uncompressed wire format: a sequence of length-prefixed labels ending with the empty root label.
+/// Names compare and hash case-insensitively, but keep the case they were created with.
+#[derive(Clone)]
+pub struct Name {
+    wire: Vec<u8>,
+}
+
+/// Reasons a name could not be created
+#[derive(PartialEq, Eq, Debug, Clone, Error)]
+pub enum InvalidName {
+    #[error("name contains an empty label")]
+    EmptyLabel,
+
+    #[error("label is {0} bytes long, but labels are limited to 63 bytes")]
+    LabelTooLong(usize),
+
+    #[error("name is {0} bytes long in wire format, but names are limited to 255 bytes")]
+    NameTooLong(usize),
+
+    #[error("name contains an invalid escape sequence")]
+    BadEscape,
+
+    #[error("name isn't a single uncompressed name in wire format")]
+    BadWireFormat,
+}
+
+impl Name {
+    pub fn root() -> Self {
+        Name { wire: vec![0] }
+    }
+
+    pub fn from_labels<L: AsRef<[u8]>>(labels: impl IntoIterator<Item = L>) -> Result<Self, InvalidName> {
+        let mut wire: Vec<u8> = Vec::new();
+
+        for label in labels {
+            let label = label.as_ref();
+
+            if label.is_empty() {
+                return Err(InvalidName::EmptyLabel);
+            }
+
+            if label.len() > MAX_LABEL_LEN {
+                return Err(InvalidName::LabelTooLong(label.len()));
+            }
+
+            wire.push(label.len() as u8);
+            wire.extend_from_slice(label);
+        }
+
+        wire.push(0);
+
+        if wire.len() > MAX_NAME_LEN {
+            return Err(InvalidName::NameTooLong(wire.len()));
+        }
+
+        Ok(Name { wire })
+    }
+
+    /// Decodes a name from uncompressed wire format. The data has to hold exactly one name, with nothing after the root label.
+    pub fn from_wire(data: &[u8]) -> Result<Self, InvalidName> {
+        let mut labels: Vec<&[u8]> = Vec::new();
+        let mut idx = 0;
+
+        loop {
+            let content_length = *data.get(idx).ok_or(InvalidName::BadWireFormat)? as usize;
+
+            if content_length == 0 {
+                break;
+            }
+
+            if content_length > MAX_LABEL_LEN {
+                return Err(InvalidName::BadWireFormat);
+            }
+
+            labels.push(data.get(idx + 1..idx + 1 + content_length).ok_or(InvalidName::BadWireFormat)?);
+            idx += 1 + content_length;
+        }
+
+        if idx + 1 != data.len() {
+            return Err(InvalidName::BadWireFormat);
+        }
+
+        Name::from_labels(labels)
+    }
+
+    pub fn as_wire(&self) -> &[u8] {
+        &self.wire
+    }
+
+    /// Iterates over the labels from left to right, leaving out the root label
+    pub fn labels(&self) -> Labels<'_> {
+        Labels { wire: &self.wire }
+    }
+
+    pub fn label_count(&self) -> usize {
+        self.labels().count()
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.wire.len() == 1
+    }
+
+    /// The name with its leftmost label removed, or `None` for the root
+    pub fn parent(&self) -> Option<Name> {
+        if self.is_root() {
+            return None;
+        }
+
+        let skip = 1 + self.wire[0] as usize;
+        Some(Name { wire: self.wire[skip..].to_vec() })
+    }
+
+    /// Whether this name is equal to or below `other`, e.g. www.example.com is a subdomain of example.com
+    pub fn is_subdomain_of(&self, other: &Name) -> bool {
+        let other_count = other.label_count();
+        self.label_count() >= other_count && self.trim_to(other_count) == *other
+    }
+
+    /// Keeps only the rightmost `label_count` labels, e.g. trimming www.example.com to 2 labels gives example.com
+    pub fn trim_to(&self, label_count: usize) -> Name {
+        let mut skip = self.label_count().saturating_sub(label_count);
+        let mut idx = 0;
+
+        while skip > 0 {
+            idx += 1 + self.wire[idx] as usize;
+            skip -= 1;
+        }
+
+        Name { wire: self.wire[idx..].to_vec() }
+    }
+
+    /// Puts `suffix` after this name's labels, e.g. appending example.com to www gives www.example.com
+    pub fn append(&self, suffix: &Name) -> Result<Name, InvalidName> {
+        Name::from_labels(self.labels().chain(suffix.labels()))
+    }
+}
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Self) -> bool {
+        self.wire.eq_ignore_ascii_case(&other.wire)
+    }
+}
+
+impl Eq for Name {}
+
+impl Hash for Name {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for byte in self.wire.iter() {
+            state.write_u8(byte.to_ascii_lowercase());
+        }
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_root() {
+            return f.write_str(".");
+        }
+
+        for label in self.labels() {
+            for byte in label {
+                match byte {
+                    b'.' | b'\\' | b'"' | b'(' | b')' | b';' | b'@' | b'$' => write!(f, "\\{}", *byte as char)?,
+                    0x21..=0x7e => write!(f, "{}", *byte as char)?,
+                    _ => write!(f, "\\{:03}", byte)?,
+                }
+            }
+
+            f.write_str(".")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Name(\"{}\")", self)
+    }
+}
+
+impl FromStr for Name {
+    type Err = InvalidName;
+
+    /// Parses the presentation format from RFC 1035 section 5.1, where `\.` is a literal dot and `\DDD` is a byte in decimal.
+    /// Names are always taken to be fully qualified, so the trailing dot is optional.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "." {
+            return Ok(Name::root());
+        }
+
+        let mut labels: Vec<Vec<u8>> = Vec::new();
+        let mut label: Vec<u8> = Vec::new();
+        let mut bytes = s.bytes();
+
+        while let Some(byte) = bytes.next() {
+            match byte {
+                b'.' => labels.push(std::mem::take(&mut label)),
+
+                b'\\' => match bytes.next() {
+                    Some(digit) if digit.is_ascii_digit() => {
+                        let digits = [digit, bytes.next().ok_or(InvalidName::BadEscape)?, bytes.next().ok_or(InvalidName::BadEscape)?];
+
+                        if !digits.iter().all(u8::is_ascii_digit) {
+                            return Err(InvalidName::BadEscape);
+                        }
+
+                        let value = digits.iter().fold(0u16, |acc, d| acc * 10 + (d - b'0') as u16);
+                        label.push(u8::try_from(value).map_err(|_| InvalidName::BadEscape)?);
+                    },
+                    Some(escaped) => label.push(escaped),
+                    None => return Err(InvalidName::BadEscape),
+                },
+
+                _ => label.push(byte),
+            }
+        }
+
+        // Without a trailing dot, the last label still needs to be added
+        if !label.is_empty() || labels.is_empty() {
+            labels.push(label);
+        }
+
+        Name::from_labels(labels)
+    }
+}
+
+/// Iterator over the labels of a `Name`
+pub struct Labels<'a> {
+    wire: &'a [u8],
+}
+
+impl<'a> Iterator for Labels<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let content_length = *self.wire.first()? as usize;
+
+        if content_length == 0 {
+            return None;
+        }
+
+        let label = &self.wire[1..1 + content_length];
+        self.wire = &self.wire[1 + content_length..];
+
+        Some(label)
+    }
+}
+
+// Generates an enum for one of the IANA DNS parameter registries, along with lossless conversions to and from its 16-bit wire value
+// and the presentation format mnemonics. Values without a variant are kept in `Unknown`, and are written as e.g. TYPE1234 (RFC 3597).
+macro_rules! registry {
+    ($(#[$meta:meta])* $name:ident, $kind:literal, $unknown_prefix:literal, { $($variant:ident = $value:literal => $mnemonic:literal,)* }) => {
+        $(#[$meta])*
+        #[allow(clippy::upper_case_acronyms)] // Names follow the IANA mnemonics
+        #[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+        pub enum $name {
+            $($variant,)*
+            Unknown(u16),
+        }
+
+        impl From<u16> for $name {
+            fn from(value: u16) -> Self {
+                match value {
+                    $($value => $name::$variant,)*
+                    _ => $name::Unknown(value),
+                }
+            }
+        }
+
+        impl From<$name> for u16 {
+            fn from(value: $name) -> Self {
+                match value {
+                    $($name::$variant => $value,)*
+                    $name::Unknown(value) => value,
+                }
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self {
+                    $($name::$variant => f.write_str($mnemonic),)*
+                    $name::Unknown(value) => write!(f, "{}{}", $unknown_prefix, value),
+                }
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = MnemonicError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                let upper = s.to_ascii_uppercase();
+
+                $(if upper == $mnemonic {
+                    return Ok($name::$variant);
+                })*
+
+                // The generic form can also name values that have a mnemonic, e.g. TYPE1 is A
+                match upper.strip_prefix($unknown_prefix).map(str::parse::<u16>) {
+                    Some(Ok(value)) => Ok($name::from(value)),
+                    _ => Err(MnemonicError { kind: $kind, value: s.to_string() }),
+                }
+            }
+        }
+    };
+}
+
+/// Returned when a string isn't the presentation format of a registry value
+#[derive(PartialEq, Eq, Debug, Clone, Error)]
+#[error("\"{value}\" is not a valid {kind}")]
+pub struct MnemonicError {
+    pub kind: &'static str,
+    pub value: String,
+}
+
+registry!(RecordType, "record type", "TYPE", {
+    A = 1 => "A",
+    NS = 2 => "NS",
+    MD = 3 => "MD",
+    MF = 4 => "MF",
+    CNAME = 5 => "CNAME",
+    SOA = 6 => "SOA",
+    MB = 7 => "MB",
+    MG = 8 => "MG",
+    MR = 9 => "MR",
+    NULL = 10 => "NULL",
+    WKS = 11 => "WKS",
+    PTR = 12 => "PTR",
+    HINFO = 13 => "HINFO",
+    MINFO = 14 => "MINFO",
+    MX = 15 => "MX",
+    TXT = 16 => "TXT",
+    RP = 17 => "RP",
+    AFSDB = 18 => "AFSDB",
+    X25 = 19 => "X25",
+    ISDN = 20 => "ISDN",
+    RT = 21 => "RT",
+    NSAP = 22 => "NSAP",
+    NSAPPTR = 23 => "NSAP-PTR",
+    SIG = 24 => "SIG",
+    KEY = 25 => "KEY",
+    PX = 26 => "PX",
+    GPOS = 27 => "GPOS",
+    AAAA = 28 => "AAAA",
+    LOC = 29 => "LOC",
+    NXT = 30 => "NXT",
+    EID = 31 => "EID",
+    NIMLOC = 32 => "NIMLOC",
+    SRV = 33 => "SRV",
+    ATMA = 34 => "ATMA",
+    NAPTR = 35 => "NAPTR",
+    KX = 36 => "KX",
+    CERT = 37 => "CERT",
+    A6 = 38 => "A6",
+    DNAME = 39 => "DNAME",
+    SINK = 40 => "SINK",
+    OPT = 41 => "OPT",
+    APL = 42 => "APL",
+    DS = 43 => "DS",
+    SSHFP = 44 => "SSHFP",
+    IPSECKEY = 45 => "IPSECKEY",
+    RRSIG = 46 => "RRSIG",
+    NSEC = 47 => "NSEC",
+    DNSKEY = 48 => "DNSKEY",
+    DHCID = 49 => "DHCID",
+    NSEC3 = 50 => "NSEC3",
+    NSEC3PARAM = 51 => "NSEC3PARAM",
+    TLSA = 52 => "TLSA",
+    SMIMEA = 53 => "SMIMEA",
+    HIP = 55 => "HIP",
+    NINFO = 56 => "NINFO",
+    RKEY = 57 => "RKEY",
+    TALINK = 58 => "TALINK",
+    CDS = 59 => "CDS",
+    CDNSKEY = 60 => "CDNSKEY",
+    OPENPGPKEY = 61 => "OPENPGPKEY",
+    CSYNC = 62 => "CSYNC",
+    ZONEMD = 63 => "ZONEMD",
+    SVCB = 64 => "SVCB",
+    HTTPS = 65 => "HTTPS",
+    DSYNC = 66 => "DSYNC",
+    SPF = 99 => "SPF",
+    UINFO = 100 => "UINFO",
+    UID = 101 => "UID",
+    GID = 102 => "GID",
+    UNSPEC = 103 => "UNSPEC",
+    NID = 104 => "NID",
+    L32 = 105 => "L32",
+    L64 = 106 => "L64",
+    LP = 107 => "LP",
+    EUI48 = 108 => "EUI48",
+    EUI64 = 109 => "EUI64",
+    NXNAME = 128 => "NXNAME",
+    TKEY = 249 => "TKEY",
+    TSIG = 250 => "TSIG",
+    IXFR = 251 => "IXFR",
+    AXFR = 252 => "AXFR",
+    MAILB = 253 => "MAILB",
+    MAILA = 254 => "MAILA",
+    ANY = 255 => "ANY",
+    URI = 256 => "URI",
+    CAA = 257 => "CAA",
+    AVC = 258 => "AVC",
+    DOA = 259 => "DOA",
+    AMTRELAY = 260 => "AMTRELAY",
+    RESINFO = 261 => "RESINFO",
+    WALLET = 262 => "WALLET",
+    CLA = 263 => "CLA",
+    IPN = 264 => "IPN",
+    TA = 32768 => "TA",
+    DLV = 32769 => "DLV",
+});
+
+registry!(ClassType, "class", "CLASS", {
+    IN = 1 => "IN",
+    CS = 2 => "CS",
+    CH = 3 => "CH",
+    HS = 4 => "HS",
+    NONE = 254 => "NONE",
+    ANY = 255 => "ANY",
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_type() {
+        struct Test {
+            label: String,
+            value: u16,
+            want_type: RecordType,
+            want_text: String,
+        }
+
+        let tests: Vec<Test> = vec![
+            Test {
+                label: "A record".to_string(),
+                value: 1,
+                want_type: RecordType::A,
+                want_text: "A".to_string(),
+            },
+
+            Test {
+                label: "hyphenated mnemonic".to_string(),
+                value: 23,
+                want_type: RecordType::NSAPPTR,
+                want_text: "NSAP-PTR".to_string(),
+            },
+
+            Test {
+                label: "HTTPS record".to_string(),
+                value: 65,
+                want_type: RecordType::HTTPS,
+                want_text: "HTTPS".to_string(),
+            },
+
+            Test {
+                label: "unassigned type".to_string(),
+                value: 1234,
+                want_type: RecordType::Unknown(1234),
+                want_text: "TYPE1234".to_string(),
+            },
+        ];
+
+        for t in tests {
+            println!("Running test \"{}\"", t.label);
+            let got_type = RecordType::from(t.value);
+            assert_eq!(got_type, t.want_type);
+            assert_eq!(u16::from(got_type), t.value);
+            assert_eq!(got_type.to_string(), t.want_text);
+            assert_eq!(t.want_text.parse::<RecordType>(), Ok(t.want_type));
+        }
+    }
+
+    #[test]
+    fn test_class_type() {
+        struct Test {
+            label: String,
+            value: u16,
+            want_class: ClassType,
+            want_text: String,
+        }
+
+        let tests: Vec<Test> = vec![
+            Test {
+                label: "internet class".to_string(),
+                value: 1,
+                want_class: ClassType::IN,
+                want_text: "IN".to_string(),
+            },
+
+            Test {
+                label: "chaos class".to_string(),
+                value: 3,
+                want_class: ClassType::CH,
+                want_text: "CH".to_string(),
+            },
+
+            Test {
+                label: "UPDATE none class".to_string(),
+                value: 254,
+                want_class: ClassType::NONE,
+                want_text: "NONE".to_string(),
+            },
+
+            Test {
+                label: "query any class".to_string(),
+                value: 255,
+                want_class: ClassType::ANY,
+                want_text: "ANY".to_string(),
+            },
+
+            Test {
+                label: "unassigned class".to_string(),
+                value: 0,
+                want_class: ClassType::Unknown(0),
+                want_text: "CLASS0".to_string(),
+            },
+        ];
+
+        for t in tests {
+            println!("Running test \"{}\"", t.label);
+            let got_class = ClassType::from(t.value);
+            assert_eq!(got_class, t.want_class);
+            assert_eq!(u16::from(got_class), t.value);
+            assert_eq!(got_class.to_string(), t.want_text);
+            assert_eq!(t.want_text.parse::<ClassType>(), Ok(t.want_class));
+        }
+    }
+
+    #[test]
+    fn test_record_type_from_str() {
+        assert_eq!("aaaa".parse::<RecordType>(), Ok(RecordType::AAAA));
+        assert_eq!("TYPE28".parse::<RecordType>(), Ok(RecordType::AAAA));
+        assert_eq!("type65280".parse::<RecordType>(), Ok(RecordType::Unknown(65280)));
+        assert!("TYPE65536".parse::<RecordType>().is_err());
+        assert!("BOGUS".parse::<RecordType>().is_err());
+    }
+
+    #[test]
+    fn test_name_from_str() {
+        struct Test {
+            label: String,
+            text: String,
+            want: Result<Vec<u8>, InvalidName>,
+        }
+
+        let tests: Vec<Test> = vec![
+            Test {
+                label: "fully qualified".to_string(),
+                text: "example.com.".to_string(),
+                want: Ok(vec![0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00]),
+            },
+
+            Test {
+                label: "no trailing dot".to_string(),
+                text: "example.com".to_string(),
+                want: Ok(vec![0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00]),
+            },
+
+            Test {
+                label: "root".to_string(),
+                text: ".".to_string(),
+                want: Ok(vec![0x00]),
+            },
+
+            Test {
+                label: "escapes".to_string(),
+                text: r"a\\.b\.c\032d.io".to_string(),
+                want: Ok(vec![0x02, b'a', b'\\', 0x05, b'b', b'.', b'c', b' ', b'd', 0x02, b'i', b'o', 0x00]),
+            },
+
+            Test {
+                label: "empty label".to_string(),
+                text: "example..com".to_string(),
+                want: Err(InvalidName::EmptyLabel),
+            },
+
+            Test {
+                label: "label too long".to_string(),
+                text: format!("{}.com", "a".repeat(64)),
+                want: Err(InvalidName::LabelTooLong(64)),
+            },
+
+            Test {
+                label: "name too long".to_string(),
+                text: [&"a".repeat(63)[..]; 4].join("."),
+                want: Err(InvalidName::NameTooLong(257)),
+            },
+
+            Test {
+                label: "escaped value too large".to_string(),
+                text: "a\\256.io".to_string(),
+                want: Err(InvalidName::BadEscape),
+            },
+
+            Test {
+                label: "short escape".to_string(),
+                text: "a\\25".to_string(),
+                want: Err(InvalidName::BadEscape),
+            },
+        ];
+
+        for t in tests {
+            println!("Running test \"{}\"", t.label);
+            let got = t.text.parse::<Name>().map(|name| name.as_wire().to_vec());
+            assert_eq!(got, t.want);
+        }
+    }
+
+    #[test]
+    fn test_name_display() {
+        let name = Name::from_labels([&b"a.b"[..], b"c\\d", b"e f", b"\xff", b"io"]).unwrap();
+        assert_eq!(name.to_string(), r"a\.b.c\\d.e\032f.\255.io.");
+        assert_eq!(name.to_string().parse::<Name>(), Ok(name));
+        assert_eq!(Name::root().to_string(), ".");
+    }
+
+    #[test]
+    fn test_name_helpers() {
+        let name: Name = "www.Example.com".parse().unwrap();
+        let zone: Name = "example.COM.".parse().unwrap();
+
+        assert_eq!(name.labels().collect::<Vec<&[u8]>>(), vec![&b"www"[..], b"Example", b"com"]);
+        assert_eq!(name.label_count(), 3);
+
+        // Comparisons ignore case, but the original case is kept
+        assert_eq!(name.trim_to(2), zone);
+        assert_eq!(name.trim_to(2).to_string(), "Example.com.");
+        assert_eq!(name.trim_to(5), name);
+        assert_eq!(name.trim_to(0), Name::root());
+        assert_eq!(name.parent(), Some(zone.clone()));
+        assert_eq!(Name::root().parent(), None);
+
+        assert!(name.is_subdomain_of(&zone));
+        assert!(zone.is_subdomain_of(&zone));
+        assert!(zone.is_subdomain_of(&Name::root()));
+        assert!(!zone.is_subdomain_of(&name));
+        assert!(!"wwwexample.com".parse::<Name>().unwrap().is_subdomain_of(&zone));
+
+        assert_eq!("www".parse::<Name>().unwrap().append(&zone), Ok(name.clone()));
+
+        let mut set = std::collections::HashSet::new();
+        set.insert(name.clone());
+        assert!(set.contains(&"WWW.EXAMPLE.COM".parse::<Name>().unwrap()));
+
+        assert_eq!(Name::from_wire(name.as_wire()), Ok(name));
+        assert_eq!(Name::from_wire(&[0x03, b'c', b'o', b'm']), Err(InvalidName::BadWireFormat));
+        assert_eq!(Name::from_wire(&[0x00, 0x00]), Err(InvalidName::BadWireFormat));
+    }
+
+    #[test]
+    fn test_client_subnet() {
+        struct Test {
+            label: String,
+            address: IpAddr,
+            source_prefix: u8,
+            want: EdnsOption,
+        }
+
+        let tests: Vec<Test> = vec![
+            Test {
+                label: "IPv4".to_string(),
+                address: "192.0.31.255".parse().unwrap(),
+                source_prefix: 20,
+                want: EdnsOption::ClientSubnet { source_prefix: 20, scope_prefix: 0, address: "192.0.16.0".parse().unwrap() },
+            },
+
+            Test {
+                label: "IPv6".to_string(),
+                address: "2001:db8:1234:5678::1".parse().unwrap(),
+                source_prefix: 56,
+                want: EdnsOption::ClientSubnet { source_prefix: 56, scope_prefix: 0, address: "2001:db8:1234:5600::".parse().unwrap() },
+            },
+
+            Test {
+                label: "IPv4-mapped IPv6".to_string(),
+                address: "::ffff:192.0.2.1".parse().unwrap(),
+                source_prefix: 24,
+                want: EdnsOption::ClientSubnet { source_prefix: 24, scope_prefix: 0, address: "192.0.2.0".parse().unwrap() },
+            },
+
+            Test {
+                label: "prefix longer than the address".to_string(),
+                address: "192.0.2.1".parse().unwrap(),
+                source_prefix: 64,
+                want: EdnsOption::ClientSubnet { source_prefix: 32, scope_prefix: 0, address: "192.0.2.1".parse().unwrap() },
+            },
+
+            Test {
+                label: "zero prefix".to_string(),
+                address: "192.0.2.1".parse().unwrap(),
+                source_prefix: 0,
+                want: EdnsOption::ClientSubnet { source_prefix: 0, scope_prefix: 0, address: "0.0.0.0".parse().unwrap() },
+            },
+        ];
+
+        for t in tests {
+            println!("Running test \"{}\"", t.label);
+            let got = EdnsOption::client_subnet(t.address, t.source_prefix);
+            assert_eq!(got, t.want);
+        }
+    }
+}
@@ -0,0 +1,411 @@
+use std::fmt;
+
+use crate::{
+    parse::{parse_header, parse_rdata, walk_name, Cursor, ParseError, HEADER_LEN},
+    types::{ClassType, DNSHeader, DNSQuestion, Name, RData, RecordType, ResourceRecord},
+};
+
+/// A message that's only decoded as far as it's read. Names, questions and records are read straight out of the datagram without
+/// allocating, so a message can be looked at (e.g. to find its question) much more cheaply than with `parse_message`.
+///
+/// Unlike `parse_message`, the OPT record is left in the additional section, and nothing is checked past the part that's read.
+#[derive(Clone, Copy)]
+pub struct MessageView<'a> {
+    data: &'a [u8],
+    header: DNSHeader,
+}
+
+impl<'a> MessageView<'a> {
+    /// Reads the header, which is the only part of the message that has to be valid up front
+    pub fn new(data: &'a [u8]) -> Result<Self, ParseError> {
+        let header = match data.get(0..HEADER_LEN) {
+            Some(bytes) => parse_header(bytes.try_into().unwrap()),
+            None => return Err(ParseError::TruncatedHeader { offset: data.len() }),
+        };
+
+        Ok(MessageView { data, header })
+    }
+
+    pub fn header(&self) -> DNSHeader {
+        self.header
+    }
+
+    pub fn questions(&self) -> Questions<'a> {
+        Questions {
+            cursor: Cursor::new(self.data, HEADER_LEN),
+            remaining: self.header.qdcount,
+        }
+    }
+
+    /// The records from the answer, authority and additional sections, in that order
+    pub fn records(&self) -> Records<'a> {
+        // The records start where the questions end, which can only be found by reading through them
+        let mut questions = self.questions();
+        let error = questions.by_ref().find_map(Result::err);
+
+        Records {
+            cursor: questions.cursor,
+            remaining: [self.header.ancount, self.header.nscount, self.header.arcount],
+            section: 0,
+            error,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Section {
+    Answer,
+    Authority,
+    Additional,
+}
+
+/// A name in a message, which might be spread out over several places by compression. It has already been checked, so its labels
+/// can be read without any more checks.
+#[derive(Clone, Copy)]
+pub struct NameView<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> NameView<'a> {
+    // Checks the name at the cursor and moves past it
+    fn read(cursor: &mut Cursor<'a>) -> Result<Self, ParseError> {
+        let offset = cursor.idx;
+        cursor.idx = walk_name(cursor.data, offset, |_| ())?;
+
+        Ok(NameView { data: cursor.data, offset })
+    }
+
+    pub fn labels(&self) -> ViewLabels<'a> {
+        ViewLabels { data: self.data, idx: self.offset }
+    }
+
+    pub fn to_name(self) -> Name {
+        Name::from_labels(self.labels()).expect("name was checked when the view was made")
+    }
+}
+
+// Names compare case-insensitively, like Name does
+impl PartialEq<Name> for NameView<'_> {
+    fn eq(&self, other: &Name) -> bool {
+        let mut ours = self.labels();
+        let mut theirs = other.labels();
+
+        loop {
+            match (ours.next(), theirs.next()) {
+                (Some(a), Some(b)) if a.eq_ignore_ascii_case(b) => continue,
+                (None, None) => return true,
+                _ => return false,
+            }
+        }
+    }
+}
+
+impl fmt::Display for NameView<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_name().fmt(f)
+    }
+}
+
+impl fmt::Debug for NameView<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NameView(\"{}\")", self)
+    }
+}
+
+/// The labels of a `NameView`, following compression pointers as they come up
+pub struct ViewLabels<'a> {
+    data: &'a [u8],
+    idx: usize,
+}
+
+impl<'a> Iterator for ViewLabels<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let content_length = self.data[self.idx];
+
+            if content_length & 0b1100_0000 == 0b1100_0000 {
+                self.idx = (u16::from_be_bytes([content_length, self.data[self.idx + 1]]) & 0b0011_1111_1111_1111) as usize;
+                continue;
+            }
+
+            if content_length == 0 {
+                return None;
+            }
+
+            let label = &self.data[self.idx + 1..self.idx + 1 + content_length as usize];
+            self.idx += 1 + content_length as usize;
+
+            return Some(label);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QuestionView<'a> {
+    pub qname: NameView<'a>,
+    pub qtype: RecordType,
+    pub qclass: ClassType,
+}
+
+impl QuestionView<'_> {
+    pub fn to_question(self) -> DNSQuestion {
+        DNSQuestion { qname: self.qname.to_name(), qtype: self.qtype, qclass: self.qclass }
+    }
+}
+
+/// Reads the questions one at a time. After an error, there are no more questions.
+pub struct Questions<'a> {
+    cursor: Cursor<'a>,
+    remaining: u16,
+}
+
+impl<'a> Iterator for Questions<'a> {
+    type Item = Result<QuestionView<'a>, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let mut read_question = || -> Result<QuestionView<'a>, ParseError> {
+            Ok(QuestionView {
+                qname: NameView::read(&mut self.cursor)?,
+                qtype: RecordType::from(self.cursor.read_u16()?),
+                qclass: ClassType::from(self.cursor.read_u16()?),
+            })
+        };
+
+        let question = read_question();
+        self.remaining = if question.is_ok() { self.remaining - 1 } else { 0 };
+
+        Some(question)
+    }
+}
+
+/// A record whose RDATA is only decoded when it's asked for
+#[derive(Debug, Clone, Copy)]
+pub struct RecordView<'a> {
+    pub section: Section,
+    pub name: NameView<'a>,
+    pub record_type: RecordType,
+    pub class: ClassType,
+    pub ttl: u32,
+    data: &'a [u8],
+    rdata_offset: usize,
+    rdlength: u16,
+}
+
+impl<'a> RecordView<'a> {
+    /// The raw RDATA. Names in it may be compressed, so they only make sense along with the rest of the message.
+    pub fn raw_rdata(&self) -> &[u8] {
+        &self.data[self.rdata_offset..self.rdata_offset + self.rdlength as usize]
+    }
+
+    pub fn rdata(&self) -> Result<RData, ParseError> {
+        // Names in the RDATA can point anywhere before them, but nothing in the RDATA can run past RDLENGTH
+        parse_rdata(&mut self.rdata_cursor(), self.record_type, self.class)
+    }
+
+    // A cursor over the RDATA, which can still follow pointers to names earlier in the message
+    pub(crate) fn rdata_cursor(&self) -> Cursor<'a> {
+        Cursor::new(&self.data[..self.rdata_offset + self.rdlength as usize], self.rdata_offset)
+    }
+
+    pub fn to_record(self) -> Result<ResourceRecord, ParseError> {
+        Ok(ResourceRecord {
+            name: self.name.to_name(),
+            record_type: self.record_type,
+            class: self.class,
+            ttl: self.ttl,
+            rdata: self.rdata()?,
+        })
+    }
+}
+
+/// Reads the records one at a time, without decoding their RDATA. After an error, there are no more records.
+pub struct Records<'a> {
+    cursor: Cursor<'a>,
+    remaining: [u16; 3], // Records left in each section
+    section: usize,
+    error: Option<ParseError>, // From reading through the questions
+}
+
+impl<'a> Records<'a> {
+    // Where the next record starts, or where the last one ended once they've all been read
+    pub(crate) fn offset(&self) -> usize {
+        self.cursor.idx
+    }
+
+    fn read_record(&mut self, section: Section) -> Result<RecordView<'a>, ParseError> {
+        let name = NameView::read(&mut self.cursor)?;
+        let record_type = RecordType::from(self.cursor.read_u16()?);
+        let class = ClassType::from(self.cursor.read_u16()?);
+        let ttl = self.cursor.read_u32()?;
+        let rdlength = self.cursor.read_u16()?;
+
+        let rdata_offset = self.cursor.idx;
+        if rdlength as usize > self.cursor.remaining() {
+            return Err(ParseError::RdlengthOverrun { offset: rdata_offset, rdlength, remaining: self.cursor.remaining() });
+        }
+
+        self.cursor.idx += rdlength as usize;
+
+        Ok(RecordView { section, name, record_type, class, ttl, data: self.cursor.data, rdata_offset, rdlength })
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<RecordView<'a>, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            self.section = self.remaining.len();
+            return Some(Err(e));
+        }
+
+        while self.remaining.get(self.section) == Some(&0) {
+            self.section += 1;
+        }
+
+        let section = match self.section {
+            0 => Section::Answer,
+            1 => Section::Authority,
+            2 => Section::Additional,
+            _ => return None,
+        };
+
+        let record = self.read_record(section);
+        match record {
+            Ok(_) => self.remaining[self.section] -= 1,
+            Err(_) => self.section = self.remaining.len(),
+        }
+
+        Some(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    use crate::parse::parse_message;
+
+    // A response with a question, an answer whose name points at the question, and an authority whose RDATA does too
+    fn response() -> Vec<u8> {
+        vec![
+            // HEADER
+            0b0000_0100, 0b1101_0010, // ID: 1234
+            0b1000_0001, // QR: 1, Opcode: 0, AA: 0, TC: 0, RD: 1
+            0b1000_0000, // RA: 1, Z: 0, AD: 0, CD: 0, RCODE: 0
+            0x00, 0x01, // QDCOUNT: 1
+            0x00, 0x01, // ANCOUNT: 1
+            0x00, 0x01, // NSCOUNT: 1
+            0x00, 0x00, // ARCOUNT: 0
+            // QUESTION
+            0x03, b'w', b'w', b'w', 0x02, b'i', b'o', 0x00,
+            0x00, 0x01, // A
+            0x00, 0x01, // IN
+            // ANSWER
+            0b1100_0000, 0b0000_1100, // Pointer to www.io
+            0x00, 0x01,
+            0x00, 0x01,
+            0x00, 0x00, 0x00, 0x3c,
+            0x00, 0x04,
+            1, 2, 3, 4,
+            // AUTHORITY
+            0b1100_0000, 0b0001_0000, // Pointer to io
+            0x00, 0x02, // NS
+            0x00, 0x01,
+            0x00, 0x00, 0x00, 0x3c,
+            0x00, 0x06,
+            0x03, b'n', b's', b'1', 0b1100_0000, 0b0001_0000, // ns1 + pointer to io
+        ]
+    }
+
+    #[test]
+    fn test_message_view() {
+        let data = response();
+        let view = MessageView::new(&data).unwrap();
+        let message = parse_message(&data).unwrap();
+
+        assert_eq!(view.header(), message.header);
+
+        let questions: Vec<QuestionView> = view.questions().collect::<Result<_, _>>().unwrap();
+        assert_eq!(questions.len(), 1);
+        assert_eq!(questions[0].qname, "WWW.io".parse::<Name>().unwrap());
+        assert_eq!(questions[0].qname.labels().collect::<Vec<&[u8]>>(), vec![&b"www"[..], b"io"]);
+        assert_eq!(questions[0].qname.to_string(), "www.io.");
+        assert_eq!(questions[0].to_question(), message.questions[0]);
+
+        let records: Vec<RecordView> = view.records().collect::<Result<_, _>>().unwrap();
+        assert_eq!(records.iter().map(|r| r.section).collect::<Vec<Section>>(), vec![Section::Answer, Section::Authority]);
+        assert_eq!(records[0].raw_rdata(), &[1, 2, 3, 4]);
+        assert_eq!(records[0].rdata(), Ok(RData::A(Ipv4Addr::new(1, 2, 3, 4))));
+        assert_eq!(records[0].to_record(), Ok(message.answers[0].clone()));
+        assert_eq!(records[1].name, "io".parse::<Name>().unwrap());
+        assert_eq!(records[1].to_record(), Ok(message.authorities[0].clone()));
+    }
+
+    #[test]
+    fn test_message_view_errors() {
+        struct Test {
+            label: String,
+            data: Vec<u8>,
+            want_questions: Vec<Result<(), ParseError>>,
+            want_records: Vec<Result<Section, ParseError>>,
+        }
+
+        let mut bad_question = response();
+        bad_question[12] = 0b0100_0000;
+
+        let mut bad_answer = response();
+        bad_answer[25] = 0b0011_0000; // Pointer forward, past the question
+
+        let mut short_authority = response();
+        short_authority.truncate(short_authority.len() - 1);
+
+        let tests: Vec<Test> = vec![
+            Test {
+                label: "bad question".to_string(),
+                data: bad_question,
+                want_questions: vec![Err(ParseError::LabelTooLong { offset: 12, length: 64 })],
+                want_records: vec![Err(ParseError::LabelTooLong { offset: 12, length: 64 })],
+            },
+
+            Test {
+                label: "bad answer".to_string(),
+                data: bad_answer,
+                want_questions: vec![Ok(())],
+                want_records: vec![Err(ParseError::BadPointer { offset: 24 })],
+            },
+
+            // Records before the bad one still come out
+            Test {
+                label: "short authority".to_string(),
+                data: short_authority,
+                want_questions: vec![Ok(())],
+                want_records: vec![
+                    Ok(Section::Answer),
+                    Err(ParseError::RdlengthOverrun { offset: 52, rdlength: 6, remaining: 5 }),
+                ],
+            },
+        ];
+
+        for t in tests {
+            println!("Running test \"{}\"", t.label);
+            let view = MessageView::new(&t.data).unwrap();
+
+            let got_questions: Vec<Result<(), ParseError>> = view.questions().map(|q| q.map(|_| ())).collect();
+            assert_eq!(got_questions, t.want_questions);
+
+            let got_records: Vec<Result<Section, ParseError>> = view.records().map(|r| r.map(|r| r.section)).collect();
+            assert_eq!(got_records, t.want_records);
+        }
+
+        assert_eq!(MessageView::new(&[0x04, 0xd2]).err(), Some(ParseError::TruncatedHeader { offset: 2 }));
+    }
+}